@@ -0,0 +1,696 @@
+//! Attribute based access control.
+//!
+//! Subject, resource, action and environment attributes are gathered by extractors and
+//! a compiled [`Policy`] decides over them, e.g.
+//! `subject.department == resource.department && action in ["read"] && env.time < 18:00`.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use axum_core::extract::FromRequestParts;
+use futures_core::future::BoxFuture;
+use http::StatusCode;
+use http::request::Parts;
+use tower_service::Service;
+use crate::GuardServiceResponse;
+
+/// An attribute value. `Time` is a time of day in seconds, written `18:00` or `18:00:30` in policies.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Time(u32),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+impl Value {
+    pub fn map<I, K, V>(entries: I) -> Self
+        where
+            I: IntoIterator<Item = (K, V)>,
+            K: Into<String>,
+            V: Into<Value>, {
+        Value::Map(entries.into_iter().map(|(key, value)| (key.into(), value.into())).collect())
+    }
+    pub fn time(hours: u32, minutes: u32, seconds: u32) -> Self {
+        Value::Time(hours * 3600 + minutes * 60 + seconds)
+    }
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Str(_) => "string",
+            Value::Time(_) => "time",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+}
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.to_string())
+    }
+}
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value)
+    }
+}
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    /// Byte offset into the source where the error was found.
+    pub offset: usize,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvalError(pub String);
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl std::error::Error for EvalError {}
+
+/// What names in an expression resolve to, and any functions beyond the built-ins.
+pub trait Scope {
+    fn get(&self, name: &str) -> Option<&Value>;
+    /// `None` when the scope doesn't define `name`, so the built-ins are tried.
+    fn call(&self, _name: &str, _args: &[Value]) -> Option<Result<Value, EvalError>> {
+        None
+    }
+}
+impl Scope for BTreeMap<String, Value> {
+    fn get(&self, name: &str) -> Option<&Value> {
+        BTreeMap::get(self, name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Lit(Value),
+    Path(Vec<String>),
+    List(Vec<Node>),
+    Call(String, Vec<Node>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Cmp(Op, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Time(u32),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Dot,
+    Comma,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let error = |message: &str, offset: usize| ParseError { message: message.to_string(), offset };
+    while i < bytes.len() {
+        let start = i;
+        let c = source[i..].chars().next().expect("i is always on a char boundary");
+        let two = source.get(i..i + 2).unwrap_or("");
+        let token = match c {
+            _ if c.is_whitespace() => {
+                i += c.len_utf8();
+                continue;
+            }
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            '.' => Token::Dot,
+            _ if two == "&&" => Token::And,
+            _ if two == "||" => Token::Or,
+            _ if two == "==" => Token::Op(Op::Eq),
+            _ if two == "!=" => Token::Op(Op::Ne),
+            _ if two == "<=" => Token::Op(Op::Le),
+            _ if two == ">=" => Token::Op(Op::Ge),
+            '<' => Token::Op(Op::Lt),
+            '>' => Token::Op(Op::Gt),
+            '!' => Token::Not,
+            '"' | '\'' => {
+                let quote = c;
+                let mut value = String::new();
+                let mut chars = source[i + 1..].char_indices();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err(error("unterminated string", start)),
+                        },
+                        Some((end, ch)) if ch == quote => {
+                            i += end + 2;
+                            break;
+                        }
+                        Some((_, ch)) => value.push(ch),
+                        None => return Err(error("unterminated string", start)),
+                    }
+                }
+                tokens.push((Token::Str(value), start));
+                continue;
+            }
+            _ if c.is_ascii_digit() || (c == '-' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit)) => {
+                i += 1;
+                while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b':') {
+                    i += 1;
+                }
+                let text = &source[start..i];
+                let token = if text.contains(':') {
+                    let parts: Vec<&str> = text.split(':').collect();
+                    let parsed: Option<Vec<u32>> = parts.iter().map(|part| part.parse().ok()).collect();
+                    match parsed.as_deref() {
+                        Some([h, m]) if *h < 24 && *m < 60 => Token::Time(h * 3600 + m * 60),
+                        Some([h, m, s]) if *h < 24 && *m < 60 && *s < 60 => Token::Time(h * 3600 + m * 60 + s),
+                        _ => return Err(error("invalid time, expected HH:MM or HH:MM:SS", start)),
+                    }
+                } else {
+                    Token::Int(text.parse().map_err(|_| error("invalid integer", start))?)
+                };
+                tokens.push((token, start));
+                continue;
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                while let Some(ch) = source[i..].chars().next().filter(|ch| ch.is_alphanumeric() || *ch == '_') {
+                    i += ch.len_utf8();
+                }
+                let word = &source[start..i];
+                let token = match word {
+                    "in" => Token::Op(Op::In),
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Ident(word.to_string()),
+                };
+                tokens.push((token, start));
+                continue;
+            }
+            _ => return Err(error(&format!("unexpected character '{}'", c), start)),
+        };
+        i += match token {
+            Token::And | Token::Or | Token::Op(Op::Eq | Op::Ne | Op::Le | Op::Ge) => 2,
+            _ => 1,
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |(_, offset)| *offset)
+    }
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError { message: message.into(), offset: self.offset() }
+    }
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, token: &Token, what: &str) -> Result<(), ParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {}", what)))
+        }
+    }
+    fn or(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.and()?;
+        while self.eat(&Token::Or) {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+    fn and(&mut self) -> Result<Node, ParseError> {
+        let mut node = self.not()?;
+        while self.eat(&Token::And) {
+            node = Node::And(Box::new(node), Box::new(self.not()?));
+        }
+        Ok(node)
+    }
+    fn not(&mut self) -> Result<Node, ParseError> {
+        if self.eat(&Token::Not) {
+            Ok(Node::Not(Box::new(self.not()?)))
+        } else {
+            self.comparison()
+        }
+    }
+    fn comparison(&mut self) -> Result<Node, ParseError> {
+        let left = self.primary()?;
+        if let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            self.pos += 1;
+            let right = self.primary()?;
+            return Ok(Node::Cmp(op, Box::new(left), Box::new(right)));
+        }
+        Ok(left)
+    }
+    fn list(&mut self, close: &Token, what: &str) -> Result<Vec<Node>, ParseError> {
+        let mut items = Vec::new();
+        if self.eat(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(self.or()?);
+            if self.eat(close) {
+                return Ok(items);
+            }
+            self.expect(&Token::Comma, what)?;
+        }
+    }
+    fn primary(&mut self) -> Result<Node, ParseError> {
+        let token = self.peek().cloned().ok_or_else(|| self.error("unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(match token {
+            Token::Str(value) => Node::Lit(Value::Str(value)),
+            Token::Int(value) => Node::Lit(Value::Int(value)),
+            Token::Time(value) => Node::Lit(Value::Time(value)),
+            Token::LParen => {
+                let node = self.or()?;
+                self.expect(&Token::RParen, "')'")?;
+                node
+            }
+            Token::LBracket => Node::List(self.list(&Token::RBracket, "',' or ']'")?),
+            Token::Ident(name) => match name.as_str() {
+                "true" => Node::Lit(Value::Bool(true)),
+                "false" => Node::Lit(Value::Bool(false)),
+                "null" => Node::Lit(Value::Null),
+                _ if self.eat(&Token::LParen) => Node::Call(name, self.list(&Token::RParen, "',' or ')'")?),
+                _ => {
+                    let mut path = vec![name];
+                    while self.eat(&Token::Dot) {
+                        match self.peek().cloned() {
+                            Some(Token::Ident(segment)) => {
+                                self.pos += 1;
+                                path.push(segment);
+                            }
+                            _ => return Err(self.error("expected attribute name after '.'")),
+                        }
+                    }
+                    Node::Path(path)
+                }
+            },
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("expected a value"));
+            }
+        })
+    }
+}
+
+/// A parsed policy expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    node: Node,
+}
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0, end: source.len() };
+        let node = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(Self { node })
+    }
+    pub fn eval(&self, scope: &dyn Scope) -> Result<Value, EvalError> {
+        eval(&self.node, scope)
+    }
+    /// Evaluates to a `bool`, anything else is an error.
+    pub fn test(&self, scope: &dyn Scope) -> Result<bool, EvalError> {
+        as_bool(self.eval(scope)?)
+    }
+}
+
+fn as_bool(value: Value) -> Result<bool, EvalError> {
+    match value {
+        Value::Bool(value) => Ok(value),
+        other => Err(EvalError(format!("expected bool, found {}", other.type_name()))),
+    }
+}
+
+fn eval(node: &Node, scope: &dyn Scope) -> Result<Value, EvalError> {
+    match node {
+        Node::Lit(value) => Ok(value.clone()),
+        Node::Path(path) => {
+            let mut value = scope.get(&path[0]).ok_or_else(|| EvalError(format!("unknown attribute {}", path[0])))?;
+            for segment in &path[1..] {
+                value = match value {
+                    Value::Map(map) => map.get(segment),
+                    _ => None,
+                }.ok_or_else(|| EvalError(format!("unknown attribute {}", path.join("."))))?;
+            }
+            Ok(value.clone())
+        }
+        Node::List(items) => Ok(Value::List(items.iter().map(|item| eval(item, scope)).collect::<Result<_, _>>()?)),
+        Node::Call(name, args) => {
+            let args = args.iter().map(|arg| eval(arg, scope)).collect::<Result<Vec<_>, _>>()?;
+            scope.call(name, &args).unwrap_or_else(|| builtin(name, &args))
+        }
+        Node::Not(node) => Ok(Value::Bool(!as_bool(eval(node, scope)?)?)),
+        Node::And(left, right) => Ok(Value::Bool(as_bool(eval(left, scope)?)? && as_bool(eval(right, scope)?)?)),
+        Node::Or(left, right) => Ok(Value::Bool(as_bool(eval(left, scope)?)? || as_bool(eval(right, scope)?)?)),
+        Node::Cmp(op, left, right) => compare(*op, eval(left, scope)?, eval(right, scope)?).map(Value::Bool),
+    }
+}
+
+fn compare(op: Op, left: Value, right: Value) -> Result<bool, EvalError> {
+    use std::cmp::Ordering;
+    let ordering = |left: &Value, right: &Value| -> Result<Ordering, EvalError> {
+        match (left, right) {
+            (Value::Int(l), Value::Int(r)) => Ok(l.cmp(r)),
+            (Value::Time(l), Value::Time(r)) => Ok(l.cmp(r)),
+            (Value::Str(l), Value::Str(r)) => Ok(l.cmp(r)),
+            (l, r) => Err(EvalError(format!("cannot order {} and {}", l.type_name(), r.type_name()))),
+        }
+    };
+    match op {
+        Op::Eq => Ok(left == right),
+        Op::Ne => Ok(left != right),
+        Op::Lt => Ok(ordering(&left, &right)?.is_lt()),
+        Op::Le => Ok(ordering(&left, &right)?.is_le()),
+        Op::Gt => Ok(ordering(&left, &right)?.is_gt()),
+        Op::Ge => Ok(ordering(&left, &right)?.is_ge()),
+        Op::In => match (&left, &right) {
+            (_, Value::List(items)) => Ok(items.contains(&left)),
+            (Value::Str(needle), Value::Str(haystack)) => Ok(haystack.contains(needle.as_str())),
+            (Value::Str(key), Value::Map(map)) => Ok(map.contains_key(key)),
+            (l, r) => Err(EvalError(format!("cannot test {} in {}", l.type_name(), r.type_name()))),
+        },
+    }
+}
+
+fn builtin(name: &str, args: &[Value]) -> Result<Value, EvalError> {
+    match (name, args) {
+        ("startsWith", [Value::Str(s), Value::Str(prefix)]) => Ok(Value::Bool(s.starts_with(prefix.as_str()))),
+        ("endsWith", [Value::Str(s), Value::Str(suffix)]) => Ok(Value::Bool(s.ends_with(suffix.as_str()))),
+        ("contains", [Value::List(items), item]) => Ok(Value::Bool(items.contains(item))),
+        ("contains", [Value::Str(s), Value::Str(part)]) => Ok(Value::Bool(s.contains(part.as_str()))),
+        ("lower", [Value::Str(s)]) => Ok(Value::Str(s.to_lowercase())),
+        ("len", [Value::List(items)]) => Ok(Value::Int(items.len() as i64)),
+        ("len", [Value::Str(s)]) => Ok(Value::Int(s.chars().count() as i64)),
+        _ => Err(EvalError(format!("unknown function {}/{}", name, args.len()))),
+    }
+}
+
+/// A compiled ABAC rule over the `subject`, `resource`, `action` and `env` attributes.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    expr: Expr,
+}
+impl Policy {
+    pub fn compile(source: &str) -> Result<Self, ParseError> {
+        Ok(Self { expr: Expr::parse(source)? })
+    }
+    pub fn evaluate(&self, attributes: &BTreeMap<String, Value>) -> Result<bool, EvalError> {
+        self.expr.test(attributes)
+    }
+}
+
+/// The default action attribute: the request method, lowercased.
+pub struct HttpAction(pub String);
+impl From<HttpAction> for Value {
+    fn from(action: HttpAction) -> Self {
+        Value::Str(action.0)
+    }
+}
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for HttpAction {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts.method.as_str().to_lowercase()))
+    }
+}
+
+/// The default environment attributes: `env.time` (UTC time of day) and `env.timestamp` (unix seconds).
+pub struct Environment {
+    pub timestamp: u64,
+}
+impl From<Environment> for Value {
+    fn from(env: Environment) -> Self {
+        Value::map([
+            ("time", Value::Time((env.timestamp % 86_400) as u32)),
+            ("timestamp", Value::Int(env.timestamp as i64)),
+        ])
+    }
+}
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Environment {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(_parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self { timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() })
+    }
+}
+
+type Extractors<Sub, Res, Act, Env> = fn() -> (Sub, Res, Act, Env);
+
+/// Evaluates a [`Policy`] over attributes extracted from the request.
+///
+/// `Sub`, `Res`, `Act` and `Env` are extractors from the `State` converting into [`Value`]s,
+/// bound in the policy as `subject`, `resource`, `action` and `env`. A rejection from any of
+/// them rejects the request; a policy that errors (e.g. a missing attribute) denies it.
+pub struct AbacService<State, Sub, Res, Act = HttpAction, Env = Environment> {
+    state: State,
+    policy: Arc<Policy>,
+    err_msg: &'static str,
+    _marker: PhantomData<Extractors<Sub, Res, Act, Env>>,
+}
+impl<State: Clone, Sub, Res, Act, Env> Clone for AbacService<State, Sub, Res, Act, Env> {
+    fn clone(&self) -> Self {
+        Self { state: self.state.clone(), policy: self.policy.clone(), err_msg: self.err_msg, _marker: PhantomData }
+    }
+}
+impl<State, Sub, Res> AbacService<State, Sub, Res> {
+    pub fn new(state: State, policy: Policy, err_msg: &'static str) -> Self {
+        Self { state, policy: Arc::new(policy), err_msg, _marker: PhantomData }
+    }
+}
+impl<State, Sub, Res, Act, Env> AbacService<State, Sub, Res, Act, Env> {
+    /// Replaces the extractors used for the `action` and `env` attributes.
+    pub fn with_context<Act2, Env2>(self) -> AbacService<State, Sub, Res, Act2, Env2> {
+        AbacService { state: self.state, policy: self.policy, err_msg: self.err_msg, _marker: PhantomData }
+    }
+}
+
+impl<State, Sub, Res, Act, Env> Service<Parts> for AbacService<State, Sub, Res, Act, Env>
+    where
+        State: Sync + Send + Clone + 'static,
+        Sub: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static,
+        Res: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static,
+        Act: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static,
+        Env: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static, {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let state = self.state.clone();
+        let policy = self.policy.clone();
+        let err_msg = self.err_msg;
+        Box::pin(async move {
+            let mut attributes = BTreeMap::new();
+            attributes.insert("subject".to_string(), Sub::from_request_parts(&mut parts, &state).await?.into());
+            attributes.insert("resource".to_string(), Res::from_request_parts(&mut parts, &state).await?.into());
+            attributes.insert("action".to_string(), Act::from_request_parts(&mut parts, &state).await?.into());
+            attributes.insert("env".to_string(), Env::from_request_parts(&mut parts, &state).await?.into());
+            let permitted = policy.evaluate(&attributes).unwrap_or(false);
+            let err_msg = if permitted { None } else { Some(String::from(err_msg)) };
+            Ok(GuardServiceResponse((permitted, err_msg), parts))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::GuardServiceExt;
+    use super::*;
+
+    struct User {
+        department: String,
+    }
+    impl From<User> for Value {
+        fn from(user: User) -> Self {
+            Value::map([("department", user.department)])
+        }
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for User {
+        type Rejection = (StatusCode, String);
+
+        async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            let department = parts.headers.get("x-department")
+                .and_then(|value| value.to_str().ok())
+                .ok_or((StatusCode::UNAUTHORIZED, "no user".to_string()))?;
+            Ok(Self { department: department.to_string() })
+        }
+    }
+    struct Report {
+        department: String,
+    }
+    impl From<Report> for Value {
+        fn from(report: Report) -> Self {
+            Value::map([("department", report.department)])
+        }
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for Report {
+        type Rejection = (StatusCode, String);
+
+        async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            let department = parts.uri.path().trim_start_matches('/').to_string();
+            Ok(Self { department })
+        }
+    }
+    struct Afternoon;
+    impl From<Afternoon> for Value {
+        fn from(_: Afternoon) -> Self {
+            Value::map([("time", Value::time(15, 30, 0))])
+        }
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for Afternoon {
+        type Rejection = (StatusCode, String);
+
+        async fn from_request_parts(_parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            Ok(Self)
+        }
+    }
+
+    fn scope(entries: Vec<(&str, Value)>) -> BTreeMap<String, Value> {
+        entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let attributes = scope(vec![
+            ("subject", Value::map([("department", "sales"), ("level", "3")])),
+            ("resource", Value::map([("department", "sales")])),
+            ("action", "read".into()),
+            ("env", Value::map([("time", Value::time(17, 59, 0))])),
+        ]);
+        let permitted = |source: &str| Policy::compile(source).unwrap().evaluate(&attributes);
+        assert_eq!(permitted(r#"subject.department == resource.department && action in ["read"] && env.time < 18:00"#), Ok(true));
+        assert_eq!(permitted(r#"action in ["write", "delete"] || !(env.time <= 17:00)"#), Ok(true));
+        assert_eq!(permitted(r#"startsWith(subject.department, "sa") and not (action == "write")"#), Ok(true));
+        assert_eq!(permitted("env.time > 18:00:00"), Ok(false));
+        assert!(permitted("subject.missing == resource.missing").is_err());
+        assert!(permitted("subject.department < 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Policy::compile("subject.department ==").unwrap_err().offset, 21);
+        assert_eq!(Policy::compile("action in [\"read\" \"write\"]").unwrap_err().offset, 18);
+        assert_eq!(Policy::compile("env.time < 25:00").unwrap_err().offset, 11);
+        assert_eq!(Policy::compile("action == 'read").unwrap_err().offset, 10);
+        assert_eq!(Policy::compile("a == b c").unwrap_err().message, "unexpected trailing input");
+        // Multi-byte characters must not split offsets mid character.
+        assert!(Policy::compile("café == 1").is_ok());
+        assert!(Policy::compile("a ==\u{a0}1").is_ok());
+        assert_eq!(Policy::compile("a == €").unwrap_err().offset, 5);
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let policy = Policy::compile(
+            r#"subject.department == resource.department && action in ["get"] && env.time < 18:00"#
+        ).unwrap();
+        let app = Router::new()
+            .route("/sales", get(|| async { StatusCode::OK }).post(|| async { StatusCode::OK }))
+            .route("/legal", get(|| async { StatusCode::OK }))
+            .layer(
+                AbacService::<(), User, Report>::new((), policy, "denied")
+                    .with_context::<HttpAction, Afternoon>()
+                    .into_layer()
+            );
+        let req = |method: &str, uri: &str| Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-department", "sales")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(req("GET", "/sales")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(req("GET", "/legal")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.oneshot(req("POST", "/sales")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,228 @@
+//! API keys read from a header, query parameter or cookie and resolved to a principal.
+use std::sync::Arc;
+use axum_core::extract::FromRequestParts;
+use http::{HeaderName, StatusCode};
+use http::request::Parts;
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConstantTimeEq};
+use crate::{cookie, Guard};
+
+/// Resolves a presented key to whoever it belongs to.
+#[async_trait::async_trait]
+pub trait KeyLookup: Send + Sync + 'static {
+    type Principal: Clone + Send + Sync + 'static;
+    async fn lookup(&self, key: &str) -> Option<Self::Principal>;
+}
+
+/// A fixed key → principal table, compared in constant time. Keys are kept as SHA-256
+/// digests, so comparing doesn't leak their length.
+#[derive(Clone)]
+pub struct StaticKeys<P> {
+    keys: Vec<([u8; 32], P)>,
+}
+impl<P> StaticKeys<P> {
+    pub fn new<I, K>(keys: I) -> Self
+        where
+            I: IntoIterator<Item = (K, P)>,
+            K: Into<String>, {
+        Self { keys: keys.into_iter().map(|(key, principal)| (Sha256::digest(key.into().as_bytes()).into(), principal)).collect() }
+    }
+}
+#[async_trait::async_trait]
+impl<P> KeyLookup for StaticKeys<P>
+    where
+        P: Clone + Send + Sync + 'static, {
+    type Principal = P;
+
+    async fn lookup(&self, key: &str) -> Option<P> {
+        let digest: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        let mut found = None;
+        for (candidate, principal) in &self.keys {
+            let matched: Choice = candidate.ct_eq(&digest);
+            if bool::from(matched) && found.is_none() {
+                found = Some(principal.clone());
+            }
+        }
+        found
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyLocation {
+    Header(HeaderName),
+    Query(String),
+    Cookie(String),
+}
+impl KeyLocation {
+    fn find(&self, parts: &Parts) -> Option<String> {
+        match self {
+            KeyLocation::Header(name) => parts.headers.get(name)?.to_str().ok().map(String::from),
+            KeyLocation::Query(name) => form_urlencoded::parse(parts.uri.query()?.as_bytes())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned()),
+            KeyLocation::Cookie(name) => cookie(parts, name),
+        }
+    }
+}
+
+/// The state passed to `GuardService::new`: where to look for the key, in order, and how to resolve it.
+pub struct ApiKeyConfig<L> {
+    locations: Vec<KeyLocation>,
+    lookup: Arc<L>,
+}
+impl<L> Clone for ApiKeyConfig<L> {
+    fn clone(&self) -> Self {
+        Self { locations: self.locations.clone(), lookup: self.lookup.clone() }
+    }
+}
+impl<L: KeyLookup> ApiKeyConfig<L> {
+    /// Looks in `X-Api-Key`, then `?api_key=`, then the `api_key` cookie.
+    pub fn new(lookup: L) -> Self {
+        Self {
+            locations: vec![
+                KeyLocation::Header(HeaderName::from_static("x-api-key")),
+                KeyLocation::Query("api_key".into()),
+                KeyLocation::Cookie("api_key".into()),
+            ],
+            lookup: Arc::new(lookup),
+        }
+    }
+    pub fn locations(mut self, locations: impl IntoIterator<Item = KeyLocation>) -> Self {
+        self.locations = locations.into_iter().collect();
+        self
+    }
+}
+
+type Allow<P> = Arc<dyn Fn(&P) -> bool + Send + Sync>;
+
+/// Extracted: the principal the presented key resolved to, if any.
+/// Expected: which principals are let through.
+///
+/// On success the principal is inserted into the request extensions.
+pub struct ApiKey<P> {
+    principal: Option<P>,
+    allow: Option<Allow<P>>,
+}
+impl<P: Clone> Clone for ApiKey<P> {
+    fn clone(&self) -> Self {
+        Self { principal: self.principal.clone(), allow: self.allow.clone() }
+    }
+}
+impl<P> ApiKey<P> {
+    /// Any key that resolves to a principal.
+    pub fn any() -> Self {
+        Self { principal: None, allow: None }
+    }
+    /// Keys whose principal satisfies `allow`.
+    pub fn matching(allow: impl Fn(&P) -> bool + Send + Sync + 'static) -> Self {
+        Self { principal: None, allow: Some(Arc::new(allow)) }
+    }
+    pub fn principal(&self) -> Option<&P> {
+        self.principal.as_ref()
+    }
+}
+impl<P> Guard for ApiKey<P>
+    where
+        P: Clone + Send + Sync + 'static, {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match (&self.principal, &expected.allow) {
+            (Some(principal), Some(allow)) => allow(principal),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        if let Some(principal) = self.principal {
+            parts.extensions.insert(principal);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<L: KeyLookup> FromRequestParts<ApiKeyConfig<L>> for ApiKey<L::Principal> {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApiKeyConfig<L>) -> Result<Self, Self::Rejection> {
+        let key = state.locations.iter().find_map(|location| location.find(parts));
+        let principal = match key {
+            Some(key) => state.lookup.lookup(&key).await,
+            None => None,
+        };
+        Ok(Self { principal, allow: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::{Extension, Router};
+    use axum::routing::get;
+    use http::{header, Request};
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Partner {
+        name: &'static str,
+        internal: bool,
+    }
+    async fn whoami(Extension(partner): Extension<Partner>) -> String {
+        partner.name.to_string()
+    }
+    fn app(locations: Option<Vec<KeyLocation>>, expected: ApiKey<Partner>) -> Router {
+        let mut config = ApiKeyConfig::new(StaticKeys::new([
+            ("acme-key", Partner { name: "acme", internal: false }),
+            ("ops-key", Partner { name: "ops", internal: true }),
+        ]));
+        if let Some(locations) = locations {
+            config = config.locations(locations);
+        }
+        Router::new()
+            .route("/", get(whoami))
+            .layer(GuardService::new(config, expected, "invalid api key").into_layer())
+    }
+    async fn body(resp: axum::response::Response) -> String {
+        String::from_utf8(hyper::body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_locations() {
+        let requests = [
+            Request::get("/").header("x-api-key", "acme-key").body(Body::empty()).unwrap(),
+            Request::get("/?api_key=acme-key").body(Body::empty()).unwrap(),
+            Request::get("/").header(header::COOKIE, "theme=dark; api_key=acme-key").body(Body::empty()).unwrap(),
+        ];
+        for req in requests {
+            let resp = app(None, ApiKey::any()).oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(body(resp).await, "acme");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restricted_locations() {
+        let req = Request::get("/?api_key=acme-key").body(Body::empty()).unwrap();
+        let locations = vec![KeyLocation::Header(HeaderName::from_static("x-api-key"))];
+        let resp = app(Some(locations), ApiKey::any()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key() {
+        let req = Request::get("/").header("x-api-key", "nope").body(Body::empty()).unwrap();
+        let resp = app(None, ApiKey::any()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_matching_principal() {
+        let expected = || ApiKey::matching(|partner: &Partner| partner.internal);
+        let req = Request::get("/").header("x-api-key", "acme-key").body(Body::empty()).unwrap();
+        let resp = app(None, expected()).oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let req = Request::get("/").header("x-api-key", "ops-key").body(Body::empty()).unwrap();
+        let resp = app(None, expected()).oneshot(req).await.unwrap();
+        assert_eq!(body(resp).await, "ops");
+    }
+}
@@ -0,0 +1,280 @@
+//! `Authorization: Basic` credentials checked by a pluggable verifier.
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use argon2::password_hash::SaltString;
+use axum_core::extract::FromRequestParts;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use http::{header, HeaderValue, StatusCode};
+use http::request::Parts;
+use crate::{authorization, Guard, GuardDenial};
+
+/// Checks a username and password, returning whoever they belong to.
+#[async_trait::async_trait]
+pub trait CredentialVerifier: Send + Sync + 'static {
+    type Principal: Clone + Send + Sync + 'static;
+    async fn verify(&self, username: &str, password: &str) -> Option<Self::Principal>;
+}
+
+/// The username of a user verified by [`PasswordHashes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicUser(pub String);
+
+/// An in-memory username → password hash table.
+///
+/// Hashes are argon2 PHC strings (`$argon2id$...`) or bcrypt hashes (`$2b$...`).
+/// Verification is deliberately slow and runs on the blocking thread pool. Unknown users
+/// are checked against a dummy hash made with the algorithm and cost of the first stored
+/// hash, so timing doesn't reveal who exists.
+#[derive(Clone, Debug, Default)]
+pub struct PasswordHashes {
+    users: HashMap<String, String>,
+    /// The hash the dummy copies its algorithm and cost from.
+    template: Option<String>,
+    dummy: Arc<OnceLock<String>>,
+}
+impl PasswordHashes {
+    pub fn new<I, U, H>(users: I) -> Self
+        where
+            I: IntoIterator<Item = (U, H)>,
+            U: Into<String>,
+            H: Into<String>, {
+        users.into_iter().fold(Self::default(), |mut hashes, (user, hash)| {
+            hashes.insert(user, hash);
+            hashes
+        })
+    }
+    pub fn insert(&mut self, username: impl Into<String>, hash: impl Into<String>) {
+        let hash = hash.into();
+        if self.template.is_none() {
+            self.template = Some(hash.clone());
+            self.dummy = Arc::default();
+        }
+        self.users.insert(username.into(), hash);
+    }
+}
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false)
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+/// Checked in place of a missing user's hash: a hash of a password no one has, with the
+/// algorithm and cost of `template` so it takes as long to check as a real one.
+fn dummy_hash(template: Option<&str>) -> String {
+    const PASSWORD: &[u8] = b"no such user";
+    let bcrypt_cost = template
+        .filter(|hash| hash.starts_with("$2"))
+        .and_then(|hash| hash.parse::<bcrypt::HashParts>().ok())
+        .map(|parts| parts.get_cost());
+    if let Some(hash) = bcrypt_cost.and_then(|cost| bcrypt::hash(PASSWORD, cost).ok()) {
+        return hash;
+    }
+    let parsed = template.and_then(|hash| PasswordHash::new(hash).ok());
+    let argon2 = parsed.as_ref()
+        .and_then(|parsed| {
+            let algorithm = argon2::Algorithm::try_from(parsed.algorithm).ok()?;
+            let version = parsed.version.map_or(Ok(Version::default()), Version::try_from).ok()?;
+            Some(Argon2::new(algorithm, version, Params::try_from(parsed).ok()?))
+        })
+        .unwrap_or_default();
+    let salt = SaltString::from_b64("ZHVtbXlzYWx0ZHVtbXk").expect("a valid b64 salt");
+    argon2.hash_password(PASSWORD, &salt).expect("params taken from a valid hash").to_string()
+}
+
+#[async_trait::async_trait]
+impl CredentialVerifier for PasswordHashes {
+    type Principal = BasicUser;
+
+    async fn verify(&self, username: &str, password: &str) -> Option<BasicUser> {
+        let hash = self.users.get(username).cloned();
+        let known = hash.is_some();
+        let (template, dummy) = (self.template.clone(), self.dummy.clone());
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || {
+            let hash = hash.unwrap_or_else(|| dummy.get_or_init(|| dummy_hash(template.as_deref())).clone());
+            verify_hash(&hash, &password)
+        })
+        .await
+        .unwrap_or(false);
+        (known && verified).then(|| BasicUser(username.to_string()))
+    }
+}
+
+/// The state passed to `GuardService::new`.
+pub struct BasicAuthConfig<V> {
+    realm: Arc<str>,
+    verifier: Arc<V>,
+}
+impl<V> Clone for BasicAuthConfig<V> {
+    fn clone(&self) -> Self {
+        Self { realm: self.realm.clone(), verifier: self.verifier.clone() }
+    }
+}
+impl<V: CredentialVerifier> BasicAuthConfig<V> {
+    pub fn new(realm: impl Into<String>, verifier: V) -> Self {
+        Self { realm: realm.into().into(), verifier: Arc::new(verifier) }
+    }
+}
+
+type Allow<P> = Arc<dyn Fn(&P) -> bool + Send + Sync>;
+
+/// Extracted: the principal the credentials were verified as, if any.
+/// Expected: which principals are let through.
+///
+/// Missing or wrong credentials fail the guard rather than rejecting, so it composes
+/// with `or`, and add a `WWW-Authenticate: Basic realm="..."` challenge.
+/// On success the principal is inserted into the request extensions.
+pub struct BasicAuth<P> {
+    principal: Option<P>,
+    realm: Option<Arc<str>>,
+    allow: Option<Allow<P>>,
+}
+impl<P: Clone> Clone for BasicAuth<P> {
+    fn clone(&self) -> Self {
+        Self { principal: self.principal.clone(), realm: self.realm.clone(), allow: self.allow.clone() }
+    }
+}
+impl<P> BasicAuth<P> {
+    pub fn any() -> Self {
+        Self { principal: None, realm: None, allow: None }
+    }
+    pub fn matching(allow: impl Fn(&P) -> bool + Send + Sync + 'static) -> Self {
+        Self { principal: None, realm: None, allow: Some(Arc::new(allow)) }
+    }
+    pub fn principal(&self) -> Option<&P> {
+        self.principal.as_ref()
+    }
+}
+impl<P> Guard for BasicAuth<P>
+    where
+        P: Clone + Send + Sync + 'static, {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match (&self.principal, &expected.allow) {
+            (Some(principal), Some(allow)) => allow(principal),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        let challenge = match &self.realm {
+            Some(realm) => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+            None => String::from("Basic"),
+        };
+        let value = HeaderValue::from_str(&challenge).unwrap_or(HeaderValue::from_static("Basic"));
+        GuardDenial::default().with_header(header::WWW_AUTHENTICATE, value)
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        if let Some(principal) = self.principal {
+            parts.extensions.insert(principal);
+        }
+    }
+}
+
+fn credentials(parts: &Parts) -> Option<(String, String)> {
+    let decoded = STANDARD.decode(authorization(parts, "Basic")?).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[async_trait::async_trait]
+impl<V: CredentialVerifier> FromRequestParts<BasicAuthConfig<V>> for BasicAuth<V::Principal> {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &BasicAuthConfig<V>) -> Result<Self, Self::Rejection> {
+        let principal = match credentials(parts) {
+            Some((username, password)) => state.verifier.verify(&username, &password).await,
+            None => None,
+        };
+        Ok(Self { principal, realm: Some(state.realm.clone()), allow: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Params, PasswordHasher, Version};
+    use argon2::password_hash::SaltString;
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use crate::bearer::BearerToken;
+    use super::*;
+
+    async fn ok() -> StatusCode {
+        StatusCode::OK
+    }
+    fn hashes() -> PasswordHashes {
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(8, 1, 1, None).unwrap());
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = argon2.hash_password(b"hunter2", &salt).unwrap().to_string();
+        let bcrypt_hash = bcrypt::hash("correct horse", 4).unwrap();
+        PasswordHashes::new([("alice", argon2_hash), ("bob", bcrypt_hash)])
+    }
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(ok))
+            .layer(
+                GuardService::new(BasicAuthConfig::new("internal", hashes()), BasicAuth::any(), "unauthorized")
+                    .or(GuardService::new((), BearerToken::any_of(["service-token"]), "unauthorized"))
+                    .into_layer()
+            )
+    }
+    fn basic(username: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password)))
+    }
+    fn request(authorization: Option<String>) -> Request<Body> {
+        let mut builder = Request::get("/");
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_argon2_and_bcrypt() {
+        let resp = app().oneshot(request(Some(basic("alice", "hunter2")))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app().oneshot(request(Some(basic("bob", "correct horse")))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app().oneshot(request(Some(basic("alice", "correct horse")))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = app().oneshot(request(Some(basic("mallory", "no such user")))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_dummy_hash_matches_stored_cost() {
+        let hashes = hashes();
+        assert!(dummy_hash(hashes.template.as_deref()).starts_with("$argon2id$v=19$m=8,t=1,p=1$"));
+        assert!(dummy_hash(Some(&bcrypt::hash("x", 5).unwrap())).starts_with("$2b$05$"));
+        assert!(dummy_hash(None).starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+    }
+
+    #[tokio::test]
+    async fn test_or_bearer() {
+        let resp = app().oneshot(request(Some("Bearer service-token".into()))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_challenges() {
+        let resp = app().oneshot(request(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let challenges = resp.headers().get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(challenges, ["Basic realm=\"internal\", charset=\"UTF-8\"", "Bearer"]);
+    }
+}
@@ -0,0 +1,180 @@
+//! `Authorization: Bearer <token>` checked against a fixed set of accepted tokens.
+use std::sync::Arc;
+use axum_core::extract::FromRequestParts;
+use http::{header, HeaderValue, StatusCode};
+use http::request::Parts;
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConstantTimeEq};
+use crate::{authorization, Guard, GuardDenial};
+
+/// Extracted: the token presented by the client, if any.
+/// Expected: the tokens accepted on the route, in plaintext or as SHA-256 digests.
+///
+/// A missing or malformed header is not a rejection, the guard simply fails,
+/// so it composes with `or`. Failures carry a `WWW-Authenticate: Bearer` challenge.
+#[derive(Clone)]
+pub struct BearerToken {
+    kind: Kind,
+}
+#[derive(Clone)]
+enum Kind {
+    Presented(Option<String>),
+    Accepted {
+        /// SHA-256 digests, so comparing doesn't leak the length of a plaintext token.
+        tokens: Arc<[[u8; 32]]>,
+        realm: Option<String>,
+    },
+}
+impl BearerToken {
+    pub fn any_of<I, T>(tokens: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<String>, {
+        Self::accepting(tokens.into_iter().map(|token| Sha256::digest(token.into().as_bytes()).into()).collect())
+    }
+    /// Accepts tokens whose hex encoded SHA-256 digest (see [`BearerToken::hash`]) is listed,
+    /// so the plaintext never has to be stored.
+    pub fn any_of_hashed<I, T>(digests: I) -> Result<Self, hex::FromHexError>
+        where
+            I: IntoIterator<Item = T>,
+            T: AsRef<str>, {
+        let tokens = digests.into_iter()
+            .map(|digest| {
+                let mut bytes = [0u8; 32];
+                hex::decode_to_slice(digest.as_ref(), &mut bytes)?;
+                Ok(bytes)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self::accepting(tokens))
+    }
+    fn accepting(tokens: Arc<[[u8; 32]]>) -> Self {
+        Self { kind: Kind::Accepted { tokens, realm: None } }
+    }
+    /// Adds `realm="..."` to the `WWW-Authenticate` challenge.
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        if let Kind::Accepted { realm: r, .. } = &mut self.kind {
+            *r = Some(realm.into());
+        }
+        self
+    }
+    /// The hex encoded SHA-256 digest of `token`, as accepted by [`BearerToken::any_of_hashed`].
+    pub fn hash(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+    /// The token presented by the client, when extracted.
+    pub fn token(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::Presented(token) => token.as_deref(),
+            Kind::Accepted { .. } => None,
+        }
+    }
+}
+impl Guard for BearerToken {
+    fn check_guard(&self, expected: &Self) -> bool {
+        let (Kind::Presented(Some(token)), Kind::Accepted { tokens, .. }) = (&self.kind, &expected.kind) else {
+            return false;
+        };
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        // Every accepted token is compared so timing doesn't reveal which one matched.
+        let matched = tokens.iter().fold(Choice::from(0), |matched, accepted| matched | accepted.ct_eq(&digest));
+        matched.into()
+    }
+    fn denial(&self, expected: &Self) -> GuardDenial {
+        let mut params = Vec::new();
+        if let Kind::Accepted { realm: Some(realm), .. } = &expected.kind {
+            params.push(format!("realm=\"{}\"", realm));
+        }
+        if let Kind::Presented(Some(_)) = &self.kind {
+            params.push(String::from("error=\"invalid_token\""));
+        }
+        let challenge = if params.is_empty() {
+            String::from("Bearer")
+        } else {
+            format!("Bearer {}", params.join(", "))
+        };
+        let value = HeaderValue::from_str(&challenge).unwrap_or(HeaderValue::from_static("Bearer"));
+        GuardDenial::default().with_header(header::WWW_AUTHENTICATE, value)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> FromRequestParts<S> for BearerToken
+    where
+        S: Send + Sync, {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let token = authorization(parts, "Bearer")
+            .filter(|token| !token.is_empty())
+            .map(String::from);
+        Ok(Self { kind: Kind::Presented(token) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    async fn ok() -> StatusCode {
+        StatusCode::OK
+    }
+    fn app(expected: BearerToken) -> Router {
+        Router::new()
+            .route("/", get(ok))
+            .layer(GuardService::new((), expected, "invalid token").into_layer())
+    }
+    fn request(authorization: Option<&'static str>) -> Request<Body> {
+        let mut builder = Request::get("/");
+        if let Some(authorization) = authorization {
+            builder = builder.header(header::AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accepted_token() {
+        let resp = app(BearerToken::any_of(["first", "second"]))
+            .oneshot(request(Some("bearer second"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_hashed_token() {
+        let expected = BearerToken::any_of_hashed([BearerToken::hash("secret")]).unwrap();
+        let resp = app(expected.clone()).oneshot(request(Some("Bearer secret"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app(expected).oneshot(request(Some("Bearer secre"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(BearerToken::any_of_hashed(["not hex"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_challenge() {
+        let expected = BearerToken::any_of(["token"]).realm("api");
+        let resp = app(expected.clone()).oneshot(request(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"api\"");
+        let resp = app(expected).oneshot(request(Some("Bearer nope"))).await.unwrap();
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer realm=\"api\", error=\"invalid_token\"");
+    }
+
+    #[tokio::test]
+    async fn test_challenge_dropped_when_or_passes() {
+        let app = Router::new()
+            .route("/", get(ok))
+            .layer(
+                GuardService::new((), BearerToken::any_of(["token"]), "invalid token")
+                    .or(GuardService::new((), BearerToken::any_of(["other"]), "invalid token"))
+                    .into_layer()
+            );
+        let resp = app.oneshot(request(Some("Bearer other"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(header::WWW_AUTHENTICATE).is_none());
+    }
+}
@@ -0,0 +1,94 @@
+//! Buffers the request body so guards that only see `Parts` can still inspect it.
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+use axum::body::HttpBody;
+use axum_core::response::{IntoResponse, Response};
+use axum_core::BoxError;
+use bytes::{Buf, Bytes, BytesMut};
+use futures_core::future::BoxFuture;
+use http::{header, Request, StatusCode};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// The raw request body, inserted into the request extensions by [`BufferBody`].
+#[derive(Clone, Debug)]
+pub struct BufferedBody(pub Bytes);
+
+#[derive(Clone, Copy, Debug)]
+pub struct BufferBodyLayer {
+    limit: usize,
+}
+impl BufferBodyLayer {
+    /// Bodies larger than `limit` bytes are rejected with `413 Payload Too Large`.
+    pub fn new(limit: usize) -> Self {
+        Self { limit }
+    }
+}
+impl<S> Layer<S> for BufferBodyLayer {
+    type Service = BufferBody<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BufferBody { inner, limit: self.limit }
+    }
+}
+
+#[derive(Clone)]
+pub struct BufferBody<S> {
+    inner: S,
+    limit: usize,
+}
+impl<S, ReqBody> Service<Request<ReqBody>> for BufferBody<S>
+    where
+        ReqBody: HttpBody + From<Bytes> + Send + 'static,
+        ReqBody::Data: Send,
+        ReqBody::Error: Into<BoxError>,
+        S: Service<Request<ReqBody>, Response = Response, Error = Infallible> + Send + Clone + 'static,
+        <S as Service<Request<ReqBody>>>::Future: Send, {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let limit = self.limit;
+        let mut inner = self.inner.clone();
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let declared = parts.headers.get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<usize>().ok());
+            if declared.is_some_and(|len| len > limit) {
+                return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+            }
+            let bytes = match collect(body, limit).await {
+                Ok(bytes) => bytes,
+                Err(status) => return Ok(status.into_response()),
+            };
+            parts.extensions.insert(BufferedBody(bytes.clone()));
+            inner.call(Request::from_parts(parts, ReqBody::from(bytes))).await
+        })
+    }
+}
+
+async fn collect<B>(body: B, limit: usize) -> Result<Bytes, StatusCode>
+    where
+        B: HttpBody,
+        B::Error: Into<BoxError>, {
+    let mut body = Box::pin(body);
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let mut chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.remaining() > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        while chunk.has_remaining() {
+            let len = chunk.chunk().len();
+            buf.extend_from_slice(chunk.chunk());
+            chunk.advance(len);
+        }
+    }
+    Ok(buf.freeze())
+}
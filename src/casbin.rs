@@ -0,0 +1,481 @@
+//! Casbin style PERM models (request, policy, matcher, effect) with CSV policies.
+//!
+//! ```text
+//! [request_definition]
+//! r = sub, obj, act
+//! [policy_definition]
+//! p = sub, obj, act
+//! [role_definition]
+//! g = _, _
+//! [policy_effect]
+//! e = some(where (p.eft == allow))
+//! [matchers]
+//! m = g(r.sub, p.sub) && keyMatch(r.obj, p.obj) && r.act == p.act
+//! ```
+//!
+//! Matchers are [`abac`](crate::abac) expressions, with `g`/`g2`.. for role links and
+//! `keyMatch`, `keyMatch2` and `regexMatch` added to the built-in functions.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use axum_core::extract::FromRequestParts;
+use futures_core::future::BoxFuture;
+use http::StatusCode;
+use http::request::Parts;
+use regex::Regex;
+use tower_service::Service;
+use crate::GuardServiceResponse;
+use crate::abac::{EvalError, Expr, ParseError, Scope, Value};
+
+#[derive(Debug)]
+pub enum CasbinError {
+    Io(std::io::Error),
+    /// A missing section or key, or an unsupported effect.
+    Model(String),
+    Matcher(ParseError),
+    /// A policy line that doesn't fit the model, with its 1-based line number.
+    Policy { line: usize, message: String },
+}
+impl fmt::Display for CasbinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CasbinError::Io(err) => write!(f, "could not read model or policy: {}", err),
+            CasbinError::Model(message) => write!(f, "invalid model: {}", message),
+            CasbinError::Matcher(err) => write!(f, "invalid matcher: {}", err),
+            CasbinError::Policy { line, message } => write!(f, "invalid policy on line {}: {}", line, message),
+        }
+    }
+}
+impl std::error::Error for CasbinError {}
+impl From<std::io::Error> for CasbinError {
+    fn from(err: std::io::Error) -> Self {
+        CasbinError::Io(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Effect {
+    /// `some(where (p.eft == allow))`
+    AllowOverride,
+    /// `!some(where (p.eft == deny))`
+    DenyOverride,
+    /// `some(where (p.eft == allow)) && !some(where (p.eft == deny))`
+    AllowAndDeny,
+    /// `priority(p.eft) || deny`
+    Priority,
+}
+impl Effect {
+    fn parse(effect: &str) -> Result<Self, CasbinError> {
+        let effect: String = effect.chars().filter(|c| !c.is_whitespace()).collect();
+        match effect.as_str() {
+            "some(where(p.eft==allow))" => Ok(Effect::AllowOverride),
+            "!some(where(p.eft==deny))" => Ok(Effect::DenyOverride),
+            "some(where(p.eft==allow))&&!some(where(p.eft==deny))" => Ok(Effect::AllowAndDeny),
+            "priority(p.eft)||deny" => Ok(Effect::Priority),
+            _ => Err(CasbinError::Model(format!("unsupported policy effect {}", effect))),
+        }
+    }
+}
+
+type RoleLinks = HashMap<(String, Option<String>), Vec<String>>;
+
+/// The matcher functions whose second argument is compiled to a regex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum PatternFn {
+    KeyMatch,
+    KeyMatch2,
+    RegexMatch,
+}
+impl PatternFn {
+    const ALL: [PatternFn; 3] = [PatternFn::KeyMatch, PatternFn::KeyMatch2, PatternFn::RegexMatch];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "keyMatch" => Some(PatternFn::KeyMatch),
+            "keyMatch2" => Some(PatternFn::KeyMatch2),
+            "regexMatch" => Some(PatternFn::RegexMatch),
+            _ => None,
+        }
+    }
+    fn name(self) -> &'static str {
+        match self {
+            PatternFn::KeyMatch => "keyMatch",
+            PatternFn::KeyMatch2 => "keyMatch2",
+            PatternFn::RegexMatch => "regexMatch",
+        }
+    }
+    fn compile(self, pattern: &str) -> Result<Regex, regex::Error> {
+        static PARAM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r":[A-Za-z0-9_]+").unwrap());
+        match self {
+            PatternFn::KeyMatch => Regex::new(&format!("^{}$", regex::escape(pattern).replace(r"\*", ".*"))),
+            PatternFn::KeyMatch2 => {
+                let pattern = regex::escape(pattern).replace(r"\*", ".*");
+                Regex::new(&format!("^{}$", PARAM.replace_all(&pattern, "[^/]+")))
+            }
+            PatternFn::RegexMatch => Regex::new(pattern),
+        }
+    }
+}
+
+/// A loaded model and policy.
+#[derive(Debug)]
+pub struct Enforcer {
+    request: Vec<String>,
+    policy: Vec<String>,
+    effect: Effect,
+    matcher: Expr,
+    rules: Vec<Vec<String>>,
+    /// Role links per `g` name: member -> roles, keyed by domain when the link has one.
+    links: HashMap<String, RoleLinks>,
+    /// Policy values compiled at load for each pattern function the matcher calls.
+    patterns: HashMap<(PatternFn, String), Regex>,
+}
+impl Enforcer {
+    pub fn new(model: &str, policy: &str) -> Result<Self, CasbinError> {
+        let sections = parse_model(model);
+        let raw = |section: &str, key: &str| -> Result<&str, CasbinError> {
+            sections.get(section).and_then(|keys| keys.get(key)).map(String::as_str)
+                .ok_or_else(|| CasbinError::Model(format!("missing {} in [{}]", key, section)))
+        };
+        let fields = |section: &str, key: &str| -> Result<Vec<String>, CasbinError> {
+            Ok(raw(section, key)?.split(',').map(|field| field.trim().to_string()).collect())
+        };
+        let request = fields("request_definition", "r")?;
+        let policy_fields = fields("policy_definition", "p")?;
+        let effect = Effect::parse(raw("policy_effect", "e")?)?;
+        let matcher_source = raw("matchers", "m")?;
+        let matcher = Expr::parse(matcher_source).map_err(CasbinError::Matcher)?;
+        let links = sections.get("role_definition")
+            .map(|keys| keys.keys().map(|name| (name.clone(), HashMap::new())).collect())
+            .unwrap_or_default();
+
+        let mut enforcer = Self {
+            request,
+            policy: policy_fields,
+            effect,
+            matcher,
+            rules: Vec::new(),
+            links,
+            patterns: HashMap::new(),
+        };
+        for (i, line) in policy.lines().enumerate() {
+            let invalid = |message: String| CasbinError::Policy { line: i + 1, message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = split_csv(line).into_iter();
+            let kind = fields.next().unwrap_or_default();
+            let fields: Vec<String> = fields.collect();
+            if kind == "p" {
+                if fields.len() != enforcer.policy.len() {
+                    return Err(invalid(format!("expected {} fields, found {}", enforcer.policy.len(), fields.len())));
+                }
+                enforcer.rules.push(fields);
+            } else if enforcer.links.contains_key(&kind) {
+                let (member, role, domain) = match fields.as_slice() {
+                    [member, role] => (member.clone(), role.clone(), None),
+                    [member, role, domain] => (member.clone(), role.clone(), Some(domain.clone())),
+                    _ => return Err(invalid(format!("{} takes 2 or 3 fields", kind))),
+                };
+                enforcer.links.entry(kind).or_default().entry((member, domain)).or_default().push(role);
+            } else {
+                return Err(invalid(format!("unknown policy type {}", kind)));
+            }
+        }
+        let called = PatternFn::ALL.into_iter()
+            .filter(|function| matcher_source.contains(&format!("{}(", function.name())));
+        for function in called {
+            for value in enforcer.rules.iter().flatten() {
+                let key = (function, value.clone());
+                if enforcer.patterns.contains_key(&key) {
+                    continue;
+                }
+                // Values that don't compile are reported when a request actually uses them.
+                if let Ok(re) = function.compile(value) {
+                    enforcer.patterns.insert(key, re);
+                }
+            }
+        }
+        Ok(enforcer)
+    }
+    pub fn from_files(model: impl AsRef<Path>, policy: impl AsRef<Path>) -> Result<Self, CasbinError> {
+        Self::new(&std::fs::read_to_string(model)?, &std::fs::read_to_string(policy)?)
+    }
+    /// Whether the request, one value per field of `request_definition`, is allowed.
+    pub fn enforce(&self, request: &[Value]) -> Result<bool, EvalError> {
+        if request.len() != self.request.len() {
+            return Err(EvalError(format!("expected {} request values, found {}", self.request.len(), request.len())));
+        }
+        let r = Value::Map(self.request.iter().cloned().zip(request.iter().cloned()).collect());
+        let mut allowed = false;
+        let mut denied = false;
+        for rule in &self.rules {
+            let p = Value::Map(self.policy.iter().cloned().zip(rule.iter().cloned().map(Value::Str)).collect());
+            let scope = MatchScope { enforcer: self, vars: BTreeMap::from([("r".to_string(), r.clone()), ("p".to_string(), p)]) };
+            if !self.matcher.test(&scope)? {
+                continue;
+            }
+            let allow = self.policy.iter().position(|field| field == "eft")
+                .is_none_or(|eft| rule[eft] != "deny");
+            if self.effect == Effect::Priority {
+                return Ok(allow);
+            }
+            allowed |= allow;
+            denied |= !allow;
+        }
+        Ok(match self.effect {
+            Effect::AllowOverride => allowed,
+            Effect::DenyOverride => !denied,
+            Effect::AllowAndDeny => allowed && !denied,
+            Effect::Priority => false,
+        })
+    }
+    fn pattern_match(&self, function: PatternFn, key: &str, pattern: &str) -> Result<bool, EvalError> {
+        if let Some(re) = self.patterns.get(&(function, pattern.to_string())) {
+            return Ok(re.is_match(key));
+        }
+        match function.compile(pattern) {
+            Ok(re) => Ok(re.is_match(key)),
+            Err(err) => Err(EvalError(format!("invalid regex {}: {}", pattern, err))),
+        }
+    }
+    fn has_role(&self, name: &str, member: &str, role: &str, domain: Option<&str>) -> bool {
+        let Some(links) = self.links.get(name) else {
+            return false;
+        };
+        let mut seen = HashSet::new();
+        let mut pending = vec![member.to_string()];
+        while let Some(current) = pending.pop() {
+            if current == role {
+                return true;
+            }
+            if seen.insert(current.clone()) {
+                let key = (current, domain.map(String::from));
+                pending.extend(links.get(&key).into_iter().flatten().cloned());
+            }
+        }
+        false
+    }
+}
+
+fn parse_model(model: &str) -> HashMap<String, BTreeMap<String, String>> {
+    let mut sections: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+    let mut section = String::new();
+    for line in model.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            section = name.trim().to_string();
+        } else if let Some((key, value)) = line.split_once('=') {
+            sections.entry(section.clone()).or_default().insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    sections
+}
+
+/// Splits on commas outside double quotes.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.into_iter().map(|field| field.trim().to_string()).collect()
+}
+
+struct MatchScope<'a> {
+    enforcer: &'a Enforcer,
+    vars: BTreeMap<String, Value>,
+}
+impl Scope for MatchScope<'_> {
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+    fn call(&self, name: &str, args: &[Value]) -> Option<Result<Value, EvalError>> {
+        if let (Some(function), [Value::Str(key), Value::Str(pattern)]) = (PatternFn::parse(name), args) {
+            return Some(self.enforcer.pattern_match(function, key, pattern).map(Value::Bool));
+        }
+        let matched = match (name, args) {
+            (_, [Value::Str(member), Value::Str(role)]) if self.enforcer.links.contains_key(name) => {
+                self.enforcer.has_role(name, member, role, None)
+            }
+            (_, [Value::Str(member), Value::Str(role), Value::Str(domain)]) if self.enforcer.links.contains_key(name) => {
+                self.enforcer.has_role(name, member, role, Some(domain))
+            }
+            _ => return None,
+        };
+        Some(Ok(Value::Bool(matched)))
+    }
+}
+
+/// Enforces an [`Enforcer`] with the request `(sub, obj, act)`: the subject from the `Sub`
+/// extractor, the request path and the request method.
+pub struct EnforcerService<State, Sub> {
+    state: State,
+    enforcer: Arc<Enforcer>,
+    err_msg: &'static str,
+    _marker: PhantomData<fn() -> Sub>,
+}
+impl<State: Clone, Sub> Clone for EnforcerService<State, Sub> {
+    fn clone(&self) -> Self {
+        Self { state: self.state.clone(), enforcer: self.enforcer.clone(), err_msg: self.err_msg, _marker: PhantomData }
+    }
+}
+impl<State, Sub> EnforcerService<State, Sub> {
+    pub fn new(state: State, enforcer: Arc<Enforcer>, err_msg: &'static str) -> Self {
+        Self { state, enforcer, err_msg, _marker: PhantomData }
+    }
+}
+impl<State, Sub> Service<Parts> for EnforcerService<State, Sub>
+    where
+        State: Sync + Send + Clone + 'static,
+        Sub: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static, {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let state = self.state.clone();
+        let enforcer = self.enforcer.clone();
+        let err_msg = self.err_msg;
+        Box::pin(async move {
+            let sub = Sub::from_request_parts(&mut parts, &state).await?.into();
+            let request = [sub, Value::from(parts.uri.path()), Value::from(parts.method.as_str())];
+            let allowed = enforcer.enforce(&request).unwrap_or(false);
+            let err_msg = if allowed { None } else { Some(String::from(err_msg)) };
+            Ok(GuardServiceResponse((allowed, err_msg), parts))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::GuardServiceExt;
+    use super::*;
+
+    const RBAC_MODEL: &str = "
+        [request_definition]
+        r = sub, obj, act
+
+        [policy_definition]
+        p = sub, obj, act
+
+        [role_definition]
+        g = _, _
+
+        [policy_effect]
+        e = some(where (p.eft == allow))
+
+        [matchers]
+        m = g(r.sub, p.sub) && keyMatch2(r.obj, p.obj) && r.act == p.act
+    ";
+    const RBAC_POLICY: &str = "
+        p, reader, /docs/:id, GET
+        p, writer, /docs/*, POST
+        g, writer, reader
+        g, alice, writer
+        g, bob, reader
+    ";
+
+    struct User(String);
+    impl From<User> for Value {
+        fn from(user: User) -> Self {
+            Value::Str(user.0)
+        }
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for User {
+        type Rejection = (StatusCode, String);
+
+        async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            let user = parts.headers.get("x-user").and_then(|user| user.to_str().ok()).unwrap_or_default();
+            Ok(Self(user.to_string()))
+        }
+    }
+
+    fn request(sub: &str, obj: &str, act: &str) -> [Value; 3] {
+        [sub.into(), obj.into(), act.into()]
+    }
+
+    #[test]
+    fn test_rbac_model() {
+        let enforcer = Enforcer::new(RBAC_MODEL, RBAC_POLICY).unwrap();
+        assert_eq!(enforcer.enforce(&request("bob", "/docs/1", "GET")), Ok(true));
+        assert_eq!(enforcer.enforce(&request("bob", "/docs/1", "POST")), Ok(false));
+        assert_eq!(enforcer.enforce(&request("alice", "/docs/1", "GET")), Ok(true));
+        assert_eq!(enforcer.enforce(&request("alice", "/docs/new/draft", "POST")), Ok(true));
+        assert_eq!(enforcer.enforce(&request("bob", "/docs/1/history", "GET")), Ok(false));
+    }
+
+    #[test]
+    fn test_deny_effect() {
+        let model = RBAC_MODEL
+            .replace("p = sub, obj, act", "p = sub, obj, act, eft")
+            .replace("some(where (p.eft == allow))", "some(where (p.eft == allow)) && !some(where (p.eft == deny))")
+            .replace("keyMatch2", "keyMatch");
+        let policy = "
+            p, reader, /docs/*, GET, allow
+            p, carol, /docs/secret, GET, deny
+            g, carol, reader
+        ";
+        let enforcer = Enforcer::new(&model, policy).unwrap();
+        assert_eq!(enforcer.enforce(&request("carol", "/docs/public", "GET")), Ok(true));
+        assert_eq!(enforcer.enforce(&request("carol", "/docs/secret", "GET")), Ok(false));
+    }
+
+    #[test]
+    fn test_compiled_patterns() {
+        let enforcer = Enforcer::new(RBAC_MODEL, RBAC_POLICY).unwrap();
+        assert!(enforcer.patterns.contains_key(&(PatternFn::KeyMatch2, "/docs/:id".to_string())));
+        assert!(!enforcer.patterns.keys().any(|(function, _)| *function == PatternFn::RegexMatch));
+
+        let model = RBAC_MODEL.replace("keyMatch2", "regexMatch");
+        let enforcer = Enforcer::new(&model, "p, bob, ^/docs/[0-9]+$, GET\np, carol, /logs/(, GET").unwrap();
+        assert_eq!(enforcer.enforce(&request("bob", "/docs/12", "GET")), Ok(true));
+        assert!(enforcer.enforce(&request("carol", "/logs/1", "GET")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_policy() {
+        let err = Enforcer::new(RBAC_MODEL, "p, reader, /docs\nq, x").unwrap_err();
+        assert!(matches!(err, CasbinError::Policy { line: 1, .. }));
+        let model = RBAC_MODEL.replace("some(where (p.eft == allow))", "max(p.eft)");
+        assert!(matches!(Enforcer::new(&model, ""), Err(CasbinError::Model(_))));
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let enforcer = Arc::new(Enforcer::new(RBAC_MODEL, RBAC_POLICY).unwrap());
+        let app = Router::new()
+            .route("/docs/:id", get(|| async { StatusCode::OK }).post(|| async { StatusCode::OK }))
+            .layer(EnforcerService::<(), User>::new((), enforcer, "forbidden").into_layer());
+        let req = |method: &str, user: &str| Request::builder()
+            .method(method)
+            .uri("/docs/7")
+            .header("x-user", user)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(req("GET", "bob")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(req("POST", "bob")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.oneshot(req("POST", "alice")).await.unwrap().status(), StatusCode::OK);
+    }
+}
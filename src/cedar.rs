@@ -0,0 +1,434 @@
+//! Cedar style policies: `permit`/`forbid` statements over the principal, action, resource
+//! and context of a request, where any matching `forbid` overrides every `permit`.
+//!
+//! ```text
+//! @id("owners-edit")
+//! permit(principal, action in ["get", "put"], resource) when { resource.owner == principal.id };
+//!
+//! @id("no-suspended")
+//! forbid(principal, action, resource) when { principal.suspended } unless { action == "get" };
+//! ```
+//!
+//! Scope constraints and conditions are [`abac`](crate::abac) expressions. As in Cedar, a
+//! statement whose condition fails to evaluate is skipped, and nothing is allowed by default.
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum_core::extract::FromRequestParts;
+use futures_core::future::BoxFuture;
+use http::StatusCode;
+use http::request::Parts;
+use tower_service::Service;
+use crate::GuardServiceResponse;
+use crate::abac::{Environment, EvalError, Expr, HttpAction, ParseError, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Effect {
+    Permit,
+    Forbid,
+}
+
+#[derive(Clone, Debug)]
+struct Statement {
+    id: String,
+    effect: Effect,
+    /// Scope constraints and `when` conditions must hold, `unless` conditions must not.
+    conditions: Vec<(bool, Expr)>,
+}
+impl Statement {
+    fn applies(&self, entities: &BTreeMap<String, Value>) -> Result<bool, EvalError> {
+        for (expected, condition) in &self.conditions {
+            if condition.test(entities)? != *expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Why a request was allowed or denied.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    /// The ids of the statements that decided: the matching `forbid`s when denied by one,
+    /// the matching `permit`s when allowed.
+    pub determining: Vec<String>,
+    /// Statements skipped because a condition failed to evaluate.
+    pub errors: Vec<(String, EvalError)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PolicySet {
+    statements: Vec<Statement>,
+}
+
+struct Scanner<'a> {
+    source: &'a str,
+    pos: usize,
+}
+impl<'a> Scanner<'a> {
+    fn error(&self, message: impl Into<String>) -> ParseError {
+        ParseError { message: message.into(), offset: self.pos }
+    }
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+    fn skip_space(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                return;
+            }
+        }
+    }
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, token: &str) -> Result<(), ParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{}'", token)))
+        }
+    }
+    fn word(&mut self) -> &'a str {
+        self.skip_space();
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+    /// The text up to the `close` matching an already consumed opener, skipping strings and nesting.
+    fn until_closing(&mut self, open: char, close: char) -> Result<(usize, &'a str), ParseError> {
+        let start = self.pos;
+        let mut depth = 0;
+        let mut quote = None;
+        let mut escaped = false;
+        for (i, c) in self.rest().char_indices() {
+            match (quote, c) {
+                (Some(_), _) if escaped => escaped = false,
+                (Some(_), '\\') => escaped = true,
+                (Some(q), _) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, _) if c == open => depth += 1,
+                (None, _) if c == close && depth == 0 => {
+                    self.pos = start + i + 1;
+                    return Ok((start, &self.source[start..start + i]));
+                }
+                (None, _) if c == close => depth -= 1,
+                _ => {}
+            }
+        }
+        Err(ParseError { message: format!("unclosed '{}'", open), offset: start - 1 })
+    }
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect("\"")?;
+        let rest = self.rest();
+        let end = rest.find('"').ok_or_else(|| self.error("unterminated string"))?;
+        self.pos += end + 1;
+        Ok(rest[..end].to_string())
+    }
+}
+
+/// Splits on commas outside brackets, parentheses and strings, keeping each part's offset.
+fn split_scope(offset: usize, head: &str) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in head.char_indices() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '[') => depth += 1,
+            (None, ')' | ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push((offset + start, &head[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push((offset + start, &head[start..]));
+    parts
+}
+
+fn expr_at(offset: usize, source: &str) -> Result<Expr, ParseError> {
+    Expr::parse(source).map_err(|err| ParseError { message: err.message, offset: offset + err.offset })
+}
+
+impl PolicySet {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut scanner = Scanner { source, pos: 0 };
+        let mut statements = Vec::new();
+        loop {
+            scanner.skip_space();
+            if scanner.rest().is_empty() {
+                break;
+            }
+            let mut id = None;
+            while scanner.eat("@") {
+                let annotation = scanner.word();
+                scanner.expect("(")?;
+                let value = scanner.string()?;
+                scanner.expect(")")?;
+                if annotation == "id" {
+                    id = Some(value);
+                }
+            }
+            scanner.skip_space();
+            let at = scanner.pos;
+            let effect = match scanner.word() {
+                "permit" => Effect::Permit,
+                "forbid" => Effect::Forbid,
+                _ => return Err(ParseError { message: "expected 'permit' or 'forbid'".into(), offset: at }),
+            };
+            scanner.expect("(")?;
+            let (offset, head) = scanner.until_closing('(', ')')?;
+            let scope = split_scope(offset, head);
+            if scope.len() != 3 {
+                return Err(ParseError { message: "expected principal, action, resource".into(), offset });
+            }
+            let mut conditions = Vec::new();
+            for ((offset, constraint), variable) in scope.into_iter().zip(["principal", "action", "resource"]) {
+                let trimmed = constraint.trim_start();
+                let offset = offset + constraint.len() - trimmed.len();
+                let rest = trimmed.strip_prefix(variable)
+                    .filter(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+                    .ok_or_else(|| ParseError { message: format!("expected '{}'", variable), offset })?;
+                if !rest.trim().is_empty() {
+                    conditions.push((true, expr_at(offset, trimmed)?));
+                }
+            }
+            loop {
+                scanner.skip_space();
+                let at = scanner.pos;
+                let expected = match scanner.word() {
+                    "when" => true,
+                    "unless" => false,
+                    _ => {
+                        scanner.pos = at;
+                        break;
+                    }
+                };
+                scanner.expect("{")?;
+                let (offset, body) = scanner.until_closing('{', '}')?;
+                conditions.push((expected, expr_at(offset, body)?));
+            }
+            scanner.expect(";")?;
+            let id = id.unwrap_or_else(|| format!("policy{}", statements.len()));
+            statements.push(Statement { id, effect, conditions });
+        }
+        Ok(Self { statements })
+    }
+    /// Decides over the `principal`, `action`, `resource` and `context` entities.
+    pub fn is_authorized(&self, entities: &BTreeMap<String, Value>) -> Decision {
+        let mut decision = Decision::default();
+        let mut permits = Vec::new();
+        let mut forbids = Vec::new();
+        for statement in &self.statements {
+            match statement.applies(entities) {
+                Ok(true) if statement.effect == Effect::Permit => permits.push(statement.id.clone()),
+                Ok(true) => forbids.push(statement.id.clone()),
+                Ok(false) => {}
+                Err(err) => decision.errors.push((statement.id.clone(), err)),
+            }
+        }
+        decision.allowed = forbids.is_empty() && !permits.is_empty();
+        decision.determining = if forbids.is_empty() { permits } else { forbids };
+        decision
+    }
+}
+
+type Extractors<Principal, Resource, Act, Ctx> = fn() -> (Principal, Resource, Act, Ctx);
+
+/// Evaluates a [`PolicySet`] over entities extracted from the request, like
+/// [`AbacService`](crate::abac::AbacService). The [`Decision`] is inserted into the
+/// request extensions so handlers and logging can see which statements decided.
+pub struct PolicySetService<State, Principal, Resource, Act = HttpAction, Ctx = Environment> {
+    state: State,
+    policies: Arc<PolicySet>,
+    err_msg: &'static str,
+    _marker: PhantomData<Extractors<Principal, Resource, Act, Ctx>>,
+}
+impl<State: Clone, Principal, Resource, Act, Ctx> Clone for PolicySetService<State, Principal, Resource, Act, Ctx> {
+    fn clone(&self) -> Self {
+        Self { state: self.state.clone(), policies: self.policies.clone(), err_msg: self.err_msg, _marker: PhantomData }
+    }
+}
+impl<State, Principal, Resource> PolicySetService<State, Principal, Resource> {
+    pub fn new(state: State, policies: PolicySet, err_msg: &'static str) -> Self {
+        Self { state, policies: Arc::new(policies), err_msg, _marker: PhantomData }
+    }
+}
+impl<State, Principal, Resource, Act, Ctx> PolicySetService<State, Principal, Resource, Act, Ctx> {
+    /// Replaces the extractors used for the `action` and `context` entities.
+    pub fn with_context<Act2, Ctx2>(self) -> PolicySetService<State, Principal, Resource, Act2, Ctx2> {
+        PolicySetService { state: self.state, policies: self.policies, err_msg: self.err_msg, _marker: PhantomData }
+    }
+}
+
+impl<State, Principal, Resource, Act, Ctx> Service<Parts> for PolicySetService<State, Principal, Resource, Act, Ctx>
+    where
+        State: Sync + Send + Clone + 'static,
+        Principal: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static,
+        Resource: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static,
+        Act: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static,
+        Ctx: FromRequestParts<State, Rejection = (StatusCode, String)> + Into<Value> + Send + 'static, {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let state = self.state.clone();
+        let policies = self.policies.clone();
+        let err_msg = self.err_msg;
+        Box::pin(async move {
+            let mut entities = BTreeMap::new();
+            entities.insert("principal".to_string(), Principal::from_request_parts(&mut parts, &state).await?.into());
+            entities.insert("resource".to_string(), Resource::from_request_parts(&mut parts, &state).await?.into());
+            entities.insert("action".to_string(), Act::from_request_parts(&mut parts, &state).await?.into());
+            entities.insert("context".to_string(), Ctx::from_request_parts(&mut parts, &state).await?.into());
+            let decision = policies.is_authorized(&entities);
+            let allowed = decision.allowed;
+            parts.extensions.insert(decision);
+            let err_msg = if allowed { None } else { Some(String::from(err_msg)) };
+            Ok(GuardServiceResponse((allowed, err_msg), parts))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::{Extension, Router};
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::GuardServiceExt;
+    use super::*;
+
+    const POLICIES: &str = r#"
+        // Owners may read and update their documents.
+        @id("owners")
+        permit(principal, action in ["get", "put"], resource) when { resource.owner == principal.id };
+        permit(principal == "auditor", action == "get", resource);
+
+        @id("suspended")
+        forbid(principal, action, resource) when { principal.suspended } unless { action == "get" };
+    "#;
+
+    struct User {
+        id: String,
+        suspended: bool,
+    }
+    impl From<User> for Value {
+        fn from(user: User) -> Self {
+            Value::map([("id", Value::from(user.id)), ("suspended", Value::from(user.suspended))])
+        }
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for User {
+        type Rejection = (StatusCode, String);
+
+        async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            let header = |name: &str| parts.headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or_default();
+            Ok(Self { id: header("x-user").to_string(), suspended: header("x-suspended") == "true" })
+        }
+    }
+    struct Document {
+        owner: String,
+    }
+    impl From<Document> for Value {
+        fn from(document: Document) -> Self {
+            Value::map([("owner", document.owner)])
+        }
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for Document {
+        type Rejection = (StatusCode, String);
+
+        async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            let owner = parts.uri.path().trim_start_matches("/docs/").to_string();
+            Ok(Self { owner })
+        }
+    }
+
+    fn entities(principal: Value, action: &str, owner: &str) -> BTreeMap<String, Value> {
+        BTreeMap::from([
+            ("principal".to_string(), principal),
+            ("action".to_string(), action.into()),
+            ("resource".to_string(), Value::map([("owner", owner)])),
+            ("context".to_string(), Value::map::<_, &str, Value>([])),
+        ])
+    }
+    fn user(id: &str, suspended: bool) -> Value {
+        Value::map([("id", Value::from(id)), ("suspended", Value::from(suspended))])
+    }
+
+    #[test]
+    fn test_forbid_overrides_permit() {
+        let policies = PolicySet::parse(POLICIES).unwrap();
+        let decision = policies.is_authorized(&entities(user("alice", false), "put", "alice"));
+        assert_eq!((decision.allowed, decision.determining), (true, vec!["owners".to_string()]));
+        let decision = policies.is_authorized(&entities(user("alice", true), "put", "alice"));
+        assert_eq!((decision.allowed, decision.determining), (false, vec!["suspended".to_string()]));
+        assert!(policies.is_authorized(&entities(user("alice", true), "get", "alice")).allowed);
+        assert!(!policies.is_authorized(&entities(user("bob", false), "get", "alice")).allowed);
+        // The auditor is a plain string, so the owner and suspension checks error and are skipped.
+        let decision = policies.is_authorized(&entities("auditor".into(), "get", "alice"));
+        assert_eq!((decision.allowed, decision.determining), (true, vec!["policy1".to_string()]));
+        assert_eq!(decision.errors.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |source: &str| PolicySet::parse(source).unwrap_err();
+        assert_eq!(err("allow(principal, action, resource);").offset, 0);
+        assert_eq!(err("permit(principal, action);").offset, 7);
+        assert_eq!(err("permit(principal, verb, resource);").offset, 18);
+        assert_eq!(err("permit(principal, action, resource) when { action == };").offset, 53);
+        assert_eq!(err("permit(principal, action, resource)").offset, 35);
+        assert_eq!(err("permit(principal, action, resource").message, "unclosed '('");
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        async fn decided_by(Extension(decision): Extension<Decision>) -> String {
+            decision.determining.join(",")
+        }
+        let service = PolicySetService::<(), User, Document>::new((), PolicySet::parse(POLICIES).unwrap(), "denied");
+        let app = Router::new()
+            .route("/docs/:owner", get(decided_by).put(decided_by))
+            .layer(service.into_layer());
+        let req = |method: &str, user: &str, suspended: &str| Request::builder()
+            .method(method)
+            .uri("/docs/alice")
+            .header("x-user", user)
+            .header("x-suspended", suspended)
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(req("PUT", "alice", "false")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&hyper::body::to_bytes(resp.into_body()).await.unwrap()[..], b"owners");
+        assert_eq!(app.clone().oneshot(req("PUT", "alice", "true")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.oneshot(req("PUT", "bob", "false")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,433 @@
+//! Guard policies per route, loaded from JSON, TOML or YAML. Requires the `config` feature.
+//!
+//! ```toml
+//! unmatched = "deny"
+//!
+//! [[routes]]
+//! path = "/admin/*rest"
+//! methods = ["POST", "DELETE"]
+//! policy = { any = ["admin", { all = ["editor", { not = "suspended" }] }] }
+//! status = 403
+//! message = "admins only"
+//! ```
+//!
+//! Leaves name guards in a [`GuardRegistry`] and may be whole expressions such as
+//! `"api_key & internal_ip"`. Paths use axum's syntax: `:name` matches one segment and a
+//! trailing `*name` the rest, and are matched against the full request path, before any
+//! `Router::nest` prefix is stripped. The first matching route applies; requests matching
+//! none are denied with `403` unless `unmatched = "allow"`.
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use axum::extract::OriginalUri;
+use futures_core::future::BoxFuture;
+use http::{Method, StatusCode};
+use http::request::Parts;
+use serde::Deserialize;
+use tower_service::Service;
+use crate::{BoxGuardService, GuardDenial, GuardLayer, GuardServiceResponse};
+use crate::expr::{ExprError, GuardExpr};
+use crate::registry::GuardRegistry;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PolicyNode {
+    Guard(String),
+    All { all: Vec<PolicyNode> },
+    Any { any: Vec<PolicyNode> },
+    Not { not: Box<PolicyNode> },
+}
+impl PolicyNode {
+    pub fn to_expr(&self) -> Result<GuardExpr, ExprError> {
+        let children = |nodes: &[PolicyNode], kind: &str| {
+            if nodes.is_empty() {
+                return Err(ExprError { message: format!("empty '{}'", kind), span: 0..0 });
+            }
+            nodes.iter().map(PolicyNode::to_expr).collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match self {
+            PolicyNode::Guard(source) => GuardExpr::parse(source)?,
+            PolicyNode::All { all } => GuardExpr::All(children(all, "all")?),
+            PolicyNode::Any { any } => GuardExpr::Any(children(any, "any")?),
+            PolicyNode::Not { not } => GuardExpr::Not(Box::new(not.to_expr()?)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct RoutePolicy {
+    pub path: String,
+    /// Every method when empty. `GET` includes `HEAD`, as it does for axum handlers.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub policy: PolicyNode,
+    /// Replaces the status of the rejection.
+    pub status: Option<u16>,
+    /// Replaces the message of the rejection.
+    pub message: Option<String>,
+}
+
+/// What happens to requests no route matches.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unmatched {
+    #[default]
+    Deny,
+    Allow,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct PolicyConfig {
+    #[serde(default)]
+    pub unmatched: Unmatched,
+    #[serde(default)]
+    pub routes: Vec<RoutePolicy>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+impl PolicyFormat {
+    /// Guessed from a `.json`, `.toml`, `.yaml` or `.yml` extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(PolicyFormat::Json),
+            "toml" => Some(PolicyFormat::Toml),
+            "yaml" | "yml" => Some(PolicyFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    /// A policy file without a `.json`, `.toml`, `.yaml` or `.yml` extension.
+    UnknownFormat(std::path::PathBuf),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    /// A route's policy doesn't parse or names unregistered guards.
+    Policy { path: String, errors: Vec<ExprError> },
+    Route { path: String, message: String },
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read policy: {}", err),
+            ConfigError::UnknownFormat(path) => write!(f, "unknown policy format for {}", path.display()),
+            ConfigError::Json(err) => write!(f, "invalid policy JSON: {}", err),
+            ConfigError::Toml(err) => write!(f, "invalid policy TOML: {}", err),
+            ConfigError::Yaml(err) => write!(f, "invalid policy YAML: {}", err),
+            ConfigError::Policy { path, errors } => {
+                let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+                write!(f, "invalid policy for {}: {}", path, errors.join(", "))
+            }
+            ConfigError::Route { path, message } => write!(f, "invalid route {}: {}", path, message),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl PolicyConfig {
+    pub fn from_json(source: &str) -> Result<Self, ConfigError> {
+        serde_json::from_str(source).map_err(ConfigError::Json)
+    }
+    pub fn from_toml(source: &str) -> Result<Self, ConfigError> {
+        toml::from_str(source).map_err(ConfigError::Toml)
+    }
+    pub fn from_yaml(source: &str) -> Result<Self, ConfigError> {
+        serde_yaml::from_str(source).map_err(ConfigError::Yaml)
+    }
+    pub fn parse(format: PolicyFormat, source: &str) -> Result<Self, ConfigError> {
+        match format {
+            PolicyFormat::Json => Self::from_json(source),
+            PolicyFormat::Toml => Self::from_toml(source),
+            PolicyFormat::Yaml => Self::from_yaml(source),
+        }
+    }
+    /// Builds every route's guard tree, failing on the first route with invalid or unknown guards.
+    pub fn build(&self, registry: &GuardRegistry) -> Result<GuardPolicies, ConfigError> {
+        let routes = self.routes.iter()
+            .map(|route| build_route(route, registry))
+            .collect::<Result<_, _>>()?;
+        Ok(GuardPolicies { routes: Arc::new(routes), unmatched: self.unmatched })
+    }
+}
+
+fn build_route(route: &RoutePolicy, registry: &GuardRegistry) -> Result<CompiledRoute, ConfigError> {
+    let invalid = |message: String| ConfigError::Route { path: route.path.clone(), message };
+    let pattern = PathPattern::parse(&route.path).map_err(invalid)?;
+    let methods = route.methods.iter()
+        .map(|method| Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| invalid(format!("invalid method {}", method))))
+        .collect::<Result<_, _>>()?;
+    let status = route.status
+        .map(|status| StatusCode::from_u16(status)
+            .ok()
+            .filter(|status| status.is_client_error() || status.is_server_error())
+            .ok_or_else(|| invalid(format!("{} is not an error status", status))))
+        .transpose()?;
+    let policy_error = |errors| ConfigError::Policy { path: route.path.clone(), errors };
+    let expr = route.policy.to_expr().map_err(|err| policy_error(vec![err]))?;
+    let unknown = registry.unknown(&expr);
+    if !unknown.is_empty() {
+        return Err(policy_error(unknown));
+    }
+    let tree = registry.build_expr(&expr).map_err(|err| policy_error(vec![err]))?;
+    Ok(CompiledRoute { pattern, methods, tree, status, message: route.message.clone() })
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    Param,
+    Rest,
+}
+
+#[derive(Clone, Debug)]
+struct PathPattern(Vec<Segment>);
+impl PathPattern {
+    fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.strip_prefix('/').ok_or("paths must start with '/'")?;
+        let segments: Vec<&str> = pattern.split('/').collect();
+        segments.iter().enumerate()
+            .map(|(i, segment)| match segment.chars().next() {
+                Some(':') => Ok(Segment::Param),
+                Some('*') if i == segments.len() - 1 => Ok(Segment::Rest),
+                Some('*') => Err("'*' is only allowed in the last segment".to_string()),
+                _ => Ok(Segment::Literal(segment.to_string())),
+            })
+            .collect::<Result<_, _>>()
+            .map(PathPattern)
+    }
+    fn matches(&self, path: &str) -> bool {
+        let mut path = path.strip_prefix('/').unwrap_or(path).split('/');
+        for segment in &self.0 {
+            match (segment, path.next()) {
+                (Segment::Rest, _) => return true,
+                (Segment::Param, Some(actual)) if !actual.is_empty() => {}
+                (Segment::Literal(literal), Some(actual)) if literal == actual => {}
+                _ => return false,
+            }
+        }
+        path.next().is_none()
+    }
+}
+
+#[derive(Clone)]
+struct CompiledRoute {
+    pattern: PathPattern,
+    methods: Vec<Method>,
+    tree: BoxGuardService,
+    status: Option<StatusCode>,
+    message: Option<String>,
+}
+
+impl CompiledRoute {
+    /// `HEAD` is answered by `GET` handlers, so it is guarded by the routes that list `GET`.
+    fn matches_method(&self, method: &Method) -> bool {
+        self.methods.is_empty()
+            || self.methods.contains(method)
+            || (*method == Method::HEAD && self.methods.contains(&Method::GET))
+    }
+}
+
+/// The guard trees of a [`PolicyConfig`], a guard service itself that runs the tree
+/// of the first route matching the request.
+#[derive(Clone, Default)]
+pub struct GuardPolicies {
+    routes: Arc<Vec<CompiledRoute>>,
+    unmatched: Unmatched,
+}
+impl GuardPolicies {
+    fn route(&self, parts: &Parts) -> Option<&CompiledRoute> {
+        let path = parts.extensions.get::<OriginalUri>().map_or(parts.uri.path(), |uri| uri.0.path());
+        self.routes.iter().find(|route| route.matches_method(&parts.method) && route.pattern.matches(path))
+    }
+}
+impl Service<Parts> for GuardPolicies {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let route = match self.route(&parts) {
+            Some(route) => route.clone(),
+            None if self.unmatched == Unmatched::Allow => return Box::pin(async move { Ok(GuardServiceResponse((true, None), parts)) }),
+            None => {
+                GuardDenial::status(StatusCode::FORBIDDEN).merge_into(&mut parts);
+                return Box::pin(async move { Ok(GuardServiceResponse((false, Some("no policy for this route".into())), parts)) });
+            }
+        };
+        let mut tree = route.tree;
+        Box::pin(async move {
+            let GuardServiceResponse(result, mut parts) = tree.call(parts).await?;
+            if result.0 {
+                return Ok(GuardServiceResponse(result, parts));
+            }
+            if let Some(status) = route.status {
+                GuardDenial::status(status).merge_into(&mut parts);
+            }
+            Ok(GuardServiceResponse((false, route.message.or(result.1)), parts))
+        })
+    }
+}
+
+/// Applies [`GuardPolicies`] to every route of a `Router`.
+pub trait RouterPolicyExt {
+    fn guard_policies(self, policies: GuardPolicies) -> Self;
+}
+impl<S, B> RouterPolicyExt for axum::Router<S, B>
+    where
+        S: Clone + Send + Sync + 'static,
+        B: axum::body::HttpBody + Send + 'static, {
+    fn guard_policies(self, policies: GuardPolicies) -> Self {
+        self.layer(GuardLayer::<_, B>::with(policies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::GuardService;
+    use crate::tests::ArbitraryData;
+    use super::*;
+
+    const TOML: &str = r#"
+        unmatched = "allow"
+
+        [[routes]]
+        path = "/admin/*rest"
+        methods = ["delete"]
+        policy = { all = ["admin", { not = "suspended" }] }
+        status = 403
+        message = "admins only"
+
+        [[routes]]
+        path = "/users/:id"
+        policy = "admin | editor"
+    "#;
+
+    fn registry() -> GuardRegistry {
+        ["admin", "editor", "suspended"].into_iter().fold(GuardRegistry::new(), |registry, name| {
+            registry.register(name, move || GuardService::new(ArbitraryData::new(name), ArbitraryData::new("yes"), "missing role"))
+        })
+    }
+    async fn send(app: &Router, method: &str, uri: &str, roles: &[&str]) -> (StatusCode, String) {
+        let req = ["admin", "editor", "suspended"].iter()
+            .fold(Request::builder().method(method).uri(uri), |req, role| {
+                req.header(*role, if roles.contains(role) { "yes" } else { "no" })
+            })
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_formats_agree() {
+        let json = r#"{"unmatched": "allow", "routes": [
+            {"path": "/admin/*rest", "methods": ["delete"], "status": 403, "message": "admins only",
+             "policy": {"all": ["admin", {"not": "suspended"}]}},
+            {"path": "/users/:id", "policy": "admin | editor"}
+        ]}"#;
+        let yaml = "
+unmatched: allow
+routes:
+  - path: /admin/*rest
+    methods: [delete]
+    policy:
+      all: [admin, {not: suspended}]
+    status: 403
+    message: admins only
+  - path: /users/:id
+    policy: admin | editor
+";
+        let toml = PolicyConfig::from_toml(TOML).unwrap();
+        assert_eq!(toml, PolicyConfig::from_json(json).unwrap());
+        assert_eq!(toml, PolicyConfig::from_yaml(yaml).unwrap());
+    }
+
+    #[test]
+    fn test_unknown_guards_fail_build() {
+        let config = PolicyConfig::from_json(r#"{"routes": [{"path": "/", "policy": {"any": ["admin", "root & vpn"]}}]}"#).unwrap();
+        match config.build(&registry()) {
+            Err(ConfigError::Policy { path, errors }) => {
+                assert_eq!(path, "/");
+                assert_eq!(errors.len(), 2);
+            }
+            _ => panic!("expected unknown guards"),
+        }
+        let config = PolicyConfig::from_json(r#"{"routes": [{"path": "/", "policy": "admin", "status": 200}]}"#).unwrap();
+        assert!(matches!(config.build(&registry()), Err(ConfigError::Route { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let policies = PolicyConfig::from_toml(TOML).unwrap().build(&registry()).unwrap();
+        let ok = || async { StatusCode::OK };
+        let app = Router::new()
+            .route("/admin/settings/mail", get(ok).delete(ok))
+            .route("/users/:id", get(ok))
+            .route("/health", get(ok))
+            .guard_policies(policies);
+
+        assert_eq!(send(&app, "DELETE", "/admin/settings/mail", &["admin"]).await.0, StatusCode::OK);
+        assert_eq!(
+            send(&app, "DELETE", "/admin/settings/mail", &["admin", "suspended"]).await,
+            (StatusCode::FORBIDDEN, "admins only".to_string())
+        );
+        assert_eq!(send(&app, "GET", "/admin/settings/mail", &[]).await.0, StatusCode::OK);
+        assert_eq!(send(&app, "GET", "/users/7", &["editor"]).await.0, StatusCode::OK);
+        assert_eq!(send(&app, "GET", "/users/7", &[]).await, (StatusCode::UNAUTHORIZED, "missing role".to_string()));
+        assert_eq!(send(&app, "GET", "/health", &[]).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_head_guarded_as_get() {
+        let config = PolicyConfig::from_json(r#"{"unmatched": "allow", "routes": [
+            {"path": "/reports", "methods": ["get"], "policy": "admin"}
+        ]}"#).unwrap();
+        let app = Router::new()
+            .route("/reports", get(|| async { StatusCode::OK }))
+            .guard_policies(config.build(&registry()).unwrap());
+        assert_eq!(send(&app, "GET", "/reports", &[]).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(&app, "HEAD", "/reports", &[]).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(&app, "HEAD", "/reports", &["admin"]).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_nested_and_unmatched() {
+        let config = PolicyConfig::from_json(r#"{"routes": [{"path": "/api/users/:id", "policy": "admin"}]}"#).unwrap();
+        assert_eq!(config.unmatched, Unmatched::Deny);
+        let ok = || async { StatusCode::OK };
+        let api = Router::new()
+            .route("/users/:id", get(ok))
+            .route("/health", get(ok))
+            .guard_policies(config.build(&registry()).unwrap());
+        let app = Router::new().nest("/api", api);
+        assert_eq!(send(&app, "GET", "/api/users/7", &[]).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(send(&app, "GET", "/api/users/7", &["admin"]).await.0, StatusCode::OK);
+        assert_eq!(send(&app, "GET", "/api/health", &["admin"]).await, (StatusCode::FORBIDDEN, "no policy for this route".to_string()));
+    }
+}
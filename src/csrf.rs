@@ -0,0 +1,224 @@
+//! CSRF protection for cookie authenticated forms: a double-submit token and
+//! `Origin`/`Referer` checks on unsafe methods.
+//!
+//! Hand the browser a token with [`mint_token`] and [`Csrf::set_cookie`], then echo it in
+//! the header or, when the guard sits behind `into_buffered_layer`, a form field.
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use futures_core::future::BoxFuture;
+use http::{header, Method, StatusCode, Uri};
+use http::request::Parts;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tower_service::Service;
+use crate::body::BufferedBody;
+use crate::{cookie, GuardDenial, GuardServiceResponse};
+
+/// A fresh random token, 32 bytes base64url encoded.
+pub fn mint_token() -> String {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).expect("the system random source is available");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// `scheme://host[:port]` of an absolute URL, the way browsers serialize `Origin`.
+fn origin_of(url: &str) -> Option<String> {
+    let uri = url.parse::<Uri>().ok()?;
+    let scheme = uri.scheme_str()?.to_ascii_lowercase();
+    let host = uri.host()?.to_ascii_lowercase();
+    Some(match uri.port_u16() {
+        Some(port) => format!("{}://{}:{}", scheme, host, port),
+        None => format!("{}://{}", scheme, host),
+    })
+}
+
+/// A guard service for unsafe methods; `GET`, `HEAD`, `OPTIONS` and `TRACE` always pass.
+///
+/// The token in the cookie must equal the one in the header or form field, and an
+/// `Origin`, or failing that `Referer`, must be one of the allowed origins. With no
+/// allowed origins set it must be the request's own host. A request carrying neither
+/// fails unless [`Csrf::allow_missing_origin`] is set. Failing is a `403`.
+#[derive(Clone, Debug)]
+pub struct Csrf {
+    cookie: &'static str,
+    header: &'static str,
+    field: &'static str,
+    origins: Arc<Vec<String>>,
+    allow_missing_origin: bool,
+    err_msg: &'static str,
+}
+impl Default for Csrf {
+    fn default() -> Self {
+        Self {
+            cookie: "csrf_token",
+            header: "x-csrf-token",
+            field: "csrf_token",
+            origins: Arc::default(),
+            allow_missing_origin: false,
+            err_msg: "csrf check failed",
+        }
+    }
+}
+impl Csrf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cookie(mut self, name: &'static str) -> Self {
+        self.cookie = name;
+        self
+    }
+    pub fn header(mut self, name: &'static str) -> Self {
+        self.header = name;
+        self
+    }
+    pub fn field(mut self, name: &'static str) -> Self {
+        self.field = name;
+        self
+    }
+    /// Origins as `scheme://host[:port]`, e.g. `https://app.example.com`.
+    pub fn allow_origins<I, T>(mut self, origins: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: AsRef<str>, {
+        self.origins = Arc::new(origins.into_iter().map(|origin| origin.as_ref().trim_end_matches('/').to_ascii_lowercase()).collect());
+        self
+    }
+    /// **Weakens the check.** Lets requests carrying neither `Origin` nor `Referer` through on
+    /// the token alone, for clients such as old browsers or `Referrer-Policy: no-referrer` pages
+    /// that send neither. A leaked or fixated token is then enough to forge a request.
+    pub fn allow_missing_origin(mut self) -> Self {
+        self.allow_missing_origin = true;
+        self
+    }
+    pub fn err_msg(mut self, err_msg: &'static str) -> Self {
+        self.err_msg = err_msg;
+        self
+    }
+    /// A `Set-Cookie` value for `token`. It is readable by scripts, which must echo it in the header.
+    pub fn set_cookie(&self, token: &str) -> String {
+        format!("{}={}; Path=/; Secure; SameSite=Strict", self.cookie, token)
+    }
+
+    fn submitted(&self, parts: &Parts) -> Option<String> {
+        if let Some(token) = parts.headers.get(self.header).and_then(|token| token.to_str().ok()) {
+            return Some(token.to_string());
+        }
+        let form = parts.headers.get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+        let body = parts.extensions.get::<BufferedBody>().filter(|_| form)?;
+        form_urlencoded::parse(&body.0)
+            .find(|(key, _)| key == self.field)
+            .map(|(_, value)| value.into_owned())
+    }
+    fn token_ok(&self, parts: &Parts) -> bool {
+        match (cookie(parts, self.cookie), self.submitted(parts)) {
+            // Digests, so comparing doesn't leak the token's length.
+            (Some(cookie), Some(submitted)) => {
+                !cookie.is_empty() && bool::from(Sha256::digest(cookie.as_bytes()).ct_eq(&Sha256::digest(submitted.as_bytes())))
+            }
+            _ => false,
+        }
+    }
+    fn origin_ok(&self, parts: &Parts) -> bool {
+        let header = |name| parts.headers.get(name).and_then(|value| value.to_str().ok());
+        let origin = match header(header::ORIGIN) {
+            // Privacy sensitive contexts send `Origin: null`, which can't be vouched for.
+            Some(origin) => origin_of(origin),
+            None => match header(header::REFERER) {
+                Some(referer) => origin_of(referer),
+                None => return self.allow_missing_origin,
+            },
+        };
+        let Some(origin) = origin else {
+            return false;
+        };
+        if self.origins.is_empty() {
+            let host = header(header::HOST).map(str::to_ascii_lowercase);
+            origin.split_once("://").is_some_and(|(_, authority)| Some(authority) == host.as_deref())
+        } else {
+            self.origins.contains(&origin)
+        }
+    }
+    fn check(&self, parts: &Parts) -> bool {
+        matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+            || (self.origin_ok(parts) && self.token_ok(parts))
+    }
+}
+
+impl Service<Parts> for Csrf {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let passed = self.check(&parts);
+        if !passed {
+            GuardDenial::status(StatusCode::FORBIDDEN).merge_into(&mut parts);
+        }
+        let err_msg = if passed { None } else { Some(String::from(self.err_msg)) };
+        Box::pin(async move { Ok(GuardServiceResponse((passed, err_msg), parts)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::post;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::GuardServiceExt;
+    use super::*;
+
+    fn app(csrf: Csrf) -> Router {
+        Router::new()
+            .route("/transfer", post(|| async { StatusCode::OK }).get(|| async { StatusCode::OK }))
+            .layer(csrf.into_buffered_layer(4096))
+    }
+    async fn status(app: &Router, req: http::request::Builder, body: impl Into<Body>) -> StatusCode {
+        app.clone().oneshot(req.uri("/transfer").body(body.into()).unwrap()).await.unwrap().status()
+    }
+
+    #[test]
+    fn test_mint_token() {
+        let token = mint_token();
+        assert_eq!(token.len(), 43);
+        assert_ne!(token, mint_token());
+        assert_eq!(Csrf::new().set_cookie("abc"), "csrf_token=abc; Path=/; Secure; SameSite=Strict");
+    }
+
+    #[tokio::test]
+    async fn test_double_submit() {
+        let app = app(Csrf::new().allow_origins(["https://app.example.com/"]));
+        let token = mint_token();
+        let cookie = format!("csrf_token={}", token);
+        let post = || Request::post("/").header("cookie", &cookie).header("origin", "https://app.example.com");
+        assert_eq!(status(&app, post().header("x-csrf-token", &token), "").await, StatusCode::OK);
+        assert_eq!(status(&app, post().header("x-csrf-token", "forged"), "").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&app, post(), "").await, StatusCode::FORBIDDEN);
+        let req = post().header("content-type", "application/x-www-form-urlencoded");
+        assert_eq!(status(&app, req, format!("amount=10&csrf_token={}", token)).await, StatusCode::OK);
+        assert_eq!(status(&app, Request::get("/"), "").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_origin() {
+        let token = mint_token();
+        let cookie = format!("csrf_token={}", token);
+        let post = || Request::post("/").header("cookie", &cookie).header("x-csrf-token", &token).header("host", "bank.example");
+        let same_host = app(Csrf::new());
+        assert_eq!(status(&same_host, post().header("origin", "https://bank.example"), "").await, StatusCode::OK);
+        assert_eq!(status(&same_host, post().header("origin", "https://evil.example"), "").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&same_host, post().header("origin", "null"), "").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&same_host, post().header("referer", "https://evil.example/page"), "").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&same_host, post(), "").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&app(Csrf::new().allow_missing_origin()), post(), "").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,304 @@
+//! A small language for guard trees, e.g. `admin | (editor & !suspended) | 2of(a, b, c)`.
+//!
+//! `!` binds tightest, then `&`, then `|`. `kof(..)` passes when at least `k` of its
+//! operands pass. Names are resolved to guard services when the expression is built.
+use std::fmt;
+use std::ops::Range;
+use crate::{BoxGuardService, GuardServiceExt, ThresholdGuardService};
+
+/// A parse or build error, with the byte range of the source it refers to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+impl ExprError {
+    fn new(message: impl Into<String>, span: Range<usize>) -> Self {
+        Self { message: message.into(), span }
+    }
+    /// The source line with the offending range underlined, for error output.
+    pub fn render(&self, source: &str) -> String {
+        let width = (self.span.end - self.span.start).max(1);
+        format!("{}\n{}{}\n{}", source, " ".repeat(self.span.start), "^".repeat(width), self.message)
+    }
+}
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}..{}", self.message, self.span.start, self.span.end)
+    }
+}
+impl std::error::Error for ExprError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GuardExpr {
+    Guard { name: String, span: Range<usize> },
+    Not(Box<GuardExpr>),
+    All(Vec<GuardExpr>),
+    Any(Vec<GuardExpr>),
+    AtLeast(usize, Vec<GuardExpr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Int(usize),
+    And,
+    Or,
+    Not,
+    Comma,
+    LParen,
+    RParen,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Range<usize>)>, ExprError> {
+    let is_name = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':');
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '&' | '|' => {
+                // `&&` and `||` are accepted as well.
+                if chars.peek().is_some_and(|(_, next)| *next == c) {
+                    chars.next();
+                }
+                if c == '&' { Token::And } else { Token::Or }
+            }
+            '!' => Token::Not,
+            ',' => Token::Comma,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            _ if c.is_ascii_digit() => {
+                let mut end = start + 1;
+                while let Some((i, _)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    end = i + 1;
+                }
+                let k = source[start..end].parse()
+                    .map_err(|_| ExprError::new("number too large", start..end))?;
+                tokens.push((Token::Int(k), start..end));
+                continue;
+            }
+            _ if is_name(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|(_, c)| is_name(*c)) {
+                    end = i + c.len_utf8();
+                }
+                tokens.push((Token::Name(source[start..end].to_string()), start..end));
+                continue;
+            }
+            _ => return Err(ExprError::new(format!("unexpected character '{}'", c), start..start + c.len_utf8())),
+        };
+        tokens.push((token, start..chars.peek().map_or(source.len(), |(i, _)| *i)));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, Range<usize>)>,
+    pos: usize,
+    end: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+    fn span(&self) -> Range<usize> {
+        self.tokens.get(self.pos).map_or(self.end..self.end, |(_, span)| span.clone())
+    }
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, token: &Token, what: &str) -> Result<(), ExprError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(ExprError::new(format!("expected {}", what), self.span()))
+        }
+    }
+    fn or(&mut self) -> Result<GuardExpr, ExprError> {
+        let mut operands = vec![self.and()?];
+        while self.eat(&Token::Or) {
+            operands.push(self.and()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { GuardExpr::Any(operands) })
+    }
+    fn and(&mut self) -> Result<GuardExpr, ExprError> {
+        let mut operands = vec![self.unary()?];
+        while self.eat(&Token::And) {
+            operands.push(self.unary()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { GuardExpr::All(operands) })
+    }
+    fn unary(&mut self) -> Result<GuardExpr, ExprError> {
+        if self.eat(&Token::Not) {
+            return Ok(GuardExpr::Not(Box::new(self.unary()?)));
+        }
+        let span = self.span();
+        match self.peek().cloned() {
+            Some(Token::Name(name)) => {
+                self.pos += 1;
+                Ok(GuardExpr::Guard { name, span })
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                self.expect(&Token::RParen, "')'")?;
+                Ok(expr)
+            }
+            Some(Token::Int(k)) => {
+                self.pos += 1;
+                if self.peek() != Some(&Token::Name("of".into())) {
+                    return Err(ExprError::new("expected 'of' after a number", self.span()));
+                }
+                self.pos += 1;
+                self.expect(&Token::LParen, "'('")?;
+                let mut operands = vec![self.or()?];
+                while self.eat(&Token::Comma) {
+                    operands.push(self.or()?);
+                }
+                self.expect(&Token::RParen, "',' or ')'")?;
+                if k == 0 || k > operands.len() {
+                    return Err(ExprError::new(format!("{}of needs between 1 and {} operands to pass", k, operands.len()), span));
+                }
+                Ok(GuardExpr::AtLeast(k, operands))
+            }
+            Some(_) => Err(ExprError::new("expected a guard name, '!', '(' or 'kof('", span)),
+            None => Err(ExprError::new("unexpected end of expression", span)),
+        }
+    }
+}
+
+impl GuardExpr {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser { tokens: tokenize(source)?, pos: 0, end: source.len() };
+        let expr = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(ExprError::new("expected '&', '|' or the end of the expression", parser.span()));
+        }
+        Ok(expr)
+    }
+    /// Every guard name in the expression, with where it appears.
+    pub fn names(&self) -> Vec<(&str, Range<usize>)> {
+        match self {
+            GuardExpr::Guard { name, span } => vec![(name.as_str(), span.clone())],
+            GuardExpr::Not(expr) => expr.names(),
+            GuardExpr::All(exprs) | GuardExpr::Any(exprs) | GuardExpr::AtLeast(_, exprs) => {
+                exprs.iter().flat_map(GuardExpr::names).collect()
+            }
+        }
+    }
+    /// Builds the guard tree, looking names up with `resolve`. Unknown names are errors.
+    pub fn build(&self, resolve: &mut impl FnMut(&str) -> Option<BoxGuardService>) -> Result<BoxGuardService, ExprError> {
+        Ok(match self {
+            GuardExpr::Guard { name, span } => resolve(name)
+                .ok_or_else(|| ExprError::new(format!("unknown guard '{}'", name), span.clone()))?,
+            GuardExpr::Not(expr) => expr.build(resolve)?.not("forbidden").boxed_guard(),
+            GuardExpr::All(exprs) => {
+                let mut guards = exprs.iter().map(|expr| expr.build(resolve));
+                let first = guards.next().expect("parser never produces an empty all")?;
+                guards.try_fold(first, |tree, guard| Ok(tree.and(guard?).boxed_guard()))?
+            }
+            GuardExpr::Any(exprs) => {
+                let mut guards = exprs.iter().map(|expr| expr.build(resolve));
+                let first = guards.next().expect("parser never produces an empty any")?;
+                guards.try_fold(first, |tree, guard| Ok(tree.or(guard?).boxed_guard()))?
+            }
+            GuardExpr::AtLeast(k, exprs) => {
+                let guards = exprs.iter().map(|expr| expr.build(resolve)).collect::<Result<_, _>>()?;
+                ThresholdGuardService::new(*k, guards).boxed_guard()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::{Request, StatusCode};
+    use tower::util::ServiceExt;
+    use crate::GuardService;
+    use crate::tests::ArbitraryData;
+    use super::*;
+
+    fn name(name: &str, span: Range<usize>) -> GuardExpr {
+        GuardExpr::Guard { name: name.into(), span }
+    }
+
+    // Each guard passes when the request has a header of the same name set to "yes".
+    fn registry(names: &[&str]) -> HashMap<String, BoxGuardService> {
+        names.iter()
+            .map(|name| {
+                let guard = GuardService::new(ArbitraryData::new(name), ArbitraryData::new("yes"), "missing");
+                (name.to_string(), guard.boxed_guard())
+            })
+            .collect()
+    }
+    async fn status(expr: &str, headers: &[&str]) -> StatusCode {
+        let guards = registry(&["admin", "editor", "suspended", "a", "b", "c"]);
+        let tree = GuardExpr::parse(expr).unwrap().build(&mut |name| guards.get(name).cloned()).unwrap();
+        let app = Router::new().route("/", get(|| async { StatusCode::OK })).layer(tree.into_layer());
+        let req = ["admin", "editor", "suspended", "a", "b", "c"].iter()
+            .fold(Request::get("/"), |req, header| {
+                req.header(*header, if headers.contains(header) { "yes" } else { "no" })
+            })
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            GuardExpr::parse("admin | (editor & !suspended) | 2of(a,b,c)").unwrap(),
+            GuardExpr::Any(vec![
+                name("admin", 0..5),
+                GuardExpr::All(vec![name("editor", 9..15), GuardExpr::Not(Box::new(name("suspended", 19..28)))]),
+                GuardExpr::AtLeast(2, vec![name("a", 36..37), name("b", 38..39), name("c", 40..41)]),
+            ])
+        );
+        assert_eq!(
+            GuardExpr::parse("a && b || c").unwrap(),
+            GuardExpr::Any(vec![GuardExpr::All(vec![name("a", 0..1), name("b", 5..6)]), name("c", 10..11)])
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |source: &str| GuardExpr::parse(source).unwrap_err();
+        assert_eq!(err("admin | ").span, 8..8);
+        assert_eq!(err("admin editor").span, 6..12);
+        assert_eq!(err("(admin | editor").span, 15..15);
+        assert_eq!(err("admin # editor").span, 6..7);
+        assert_eq!(err("4of(a, b)").span, 0..1);
+        assert_eq!(err("2 (a, b)").message, "expected 'of' after a number");
+        assert_eq!(err("admin editor").render("admin editor"), "admin editor\n      ^^^^^^\nexpected '&', '|' or the end of the expression");
+    }
+
+    #[test]
+    fn test_unknown_guard() {
+        let guards = registry(&["admin"]);
+        let err = GuardExpr::parse("admin | ghost").unwrap()
+            .build(&mut |name| guards.get(name).cloned())
+            .err()
+            .unwrap();
+        assert_eq!(err, ExprError::new("unknown guard 'ghost'", 8..13));
+    }
+
+    #[tokio::test]
+    async fn test_evaluate() {
+        let expr = "admin | (editor & !suspended) | 2of(a,b,c)";
+        assert_eq!(status(expr, &["admin"]).await, StatusCode::OK);
+        assert_eq!(status(expr, &["editor"]).await, StatusCode::OK);
+        assert_eq!(status(expr, &["editor", "suspended"]).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(expr, &["a", "c"]).await, StatusCode::OK);
+        assert_eq!(status(expr, &["b"]).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,244 @@
+//! Client IP allow and deny lists, with the client found behind trusted proxies.
+use std::net::{IpAddr, SocketAddr};
+use axum::extract::ConnectInfo;
+use axum_core::extract::FromRequestParts;
+use http::StatusCode;
+use http::request::Parts;
+use ipnet::{AddrParseError, IpNet};
+use crate::{Guard, GuardDenial};
+
+/// Parses a CIDR range, or a single address as a range of one.
+pub fn parse_net(net: &str) -> Result<IpNet, AddrParseError> {
+    net.parse().or_else(|err| net.parse::<IpAddr>().map(IpNet::from).map_err(|_| err))
+}
+
+fn parse_nets<I, T>(nets: I) -> Result<Vec<IpNet>, AddrParseError>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>, {
+    nets.into_iter().map(|net| parse_net(net.as_ref())).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyHeader {
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=...`.
+    Forwarded,
+}
+
+/// The state passed to `GuardService::new`: which peers are proxies whose forwarding
+/// header is believed. With none trusted, the client is the peer from `ConnectInfo`,
+/// which requires `into_make_service_with_connect_info::<SocketAddr>()`.
+#[derive(Clone, Debug)]
+pub struct IpConfig {
+    trusted: Vec<IpNet>,
+    header: ProxyHeader,
+}
+impl Default for IpConfig {
+    fn default() -> Self {
+        Self { trusted: Vec::new(), header: ProxyHeader::XForwardedFor }
+    }
+}
+impl IpConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn trust_proxies<I, T>(mut self, proxies: I) -> Result<Self, AddrParseError>
+        where
+            I: IntoIterator<Item = T>,
+            T: AsRef<str>, {
+        self.trusted = parse_nets(proxies)?;
+        Ok(self)
+    }
+    pub fn header(mut self, header: ProxyHeader) -> Self {
+        self.header = header;
+        self
+    }
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+    /// The hops the forwarding header lists, the original client first. A hop that isn't an
+    /// address, such as `unknown` or an obfuscated `_node`, is kept as `None`.
+    fn forwarded_for(&self, parts: &Parts) -> Vec<Option<IpAddr>> {
+        let values = parts.headers.get_all(match self.header {
+            ProxyHeader::XForwardedFor => "x-forwarded-for",
+            ProxyHeader::Forwarded => "forwarded",
+        });
+        let entries = values.iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty());
+        match self.header {
+            ProxyHeader::XForwardedFor => entries.map(parse_node).collect(),
+            ProxyHeader::Forwarded => entries
+                .map(|entry| {
+                    entry.split(';')
+                        .filter_map(|pair| pair.trim().split_once('='))
+                        .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                        .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+                })
+                .collect(),
+        }
+    }
+    /// The client address as [`ClientIp`] resolves it, for other guards keyed by client,
+    /// e.g. [`crate::rate_limit::RateLimit::by_ip`]. `None` without `ConnectInfo`, or when
+    /// a trusted proxy forwarded a hop that isn't an address.
+    pub fn client(&self, parts: &Parts) -> Option<IpAddr> {
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>()?.0.ip().to_canonical();
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+        // Walk back from the nearest hop; the first address not ours is the client. A hop
+        // that can't be read ends the walk, as skipping it would trust what the client wrote.
+        let hops = self.forwarded_for(parts);
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(ip) if self.is_trusted(ip) => continue,
+                hop => return *hop,
+            }
+        }
+        hops.first().copied().unwrap_or(Some(peer))
+    }
+}
+
+/// An address as it appears in a forwarding header: `1.2.3.4`, `1.2.3.4:80`, `[::1]:80` or `::1`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let ip = node.parse::<IpAddr>().ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.split_once(']')?.0.parse().ok())?;
+    Some(ip.to_canonical())
+}
+
+/// The client address an [`IpGuard`] passed, inserted into the request extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Extracted: the client address.
+/// Expected: the ranges allowed and denied. A denied range wins over an allowed one,
+/// and with no allowed ranges everything not denied is allowed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpGuard {
+    ip: Option<IpAddr>,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+impl IpGuard {
+    pub fn allow<I, T>(nets: I) -> Result<Self, AddrParseError>
+        where
+            I: IntoIterator<Item = T>,
+            T: AsRef<str>, {
+        Ok(Self { allow: parse_nets(nets)?, ..Self::default() })
+    }
+    pub fn deny<I, T>(mut self, nets: I) -> Result<Self, AddrParseError>
+        where
+            I: IntoIterator<Item = T>,
+            T: AsRef<str>, {
+        self.deny = parse_nets(nets)?;
+        Ok(self)
+    }
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+}
+impl Guard for IpGuard {
+    fn check_guard(&self, expected: &Self) -> bool {
+        let Some(ip) = self.ip else {
+            return false;
+        };
+        !expected.deny.iter().any(|net| net.contains(&ip))
+            && (expected.allow.is_empty() || expected.allow.iter().any(|net| net.contains(&ip)))
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        GuardDenial::status(StatusCode::FORBIDDEN)
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        if let Some(ip) = self.ip {
+            parts.extensions.insert(ClientIp(ip));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<IpConfig> for IpGuard {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &IpConfig) -> Result<Self, Self::Rejection> {
+        Ok(Self { ip: state.client(parts), ..Self::default() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    fn app(config: IpConfig) -> Router {
+        let expected = IpGuard::allow(["10.8.0.0/16", "fd00::/8"]).unwrap().deny(["10.8.13.0/24"]).unwrap();
+        Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(GuardService::new(config, expected, "not on the vpn").into_layer())
+    }
+    async fn status(app: Router, peer: &str, header: Option<(&str, &str)>) -> StatusCode {
+        let mut req = Request::get("/");
+        if let Some((name, value)) = header {
+            req = req.header(name, value);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_allow_and_deny() {
+        assert_eq!(status(app(IpConfig::new()), "10.8.1.2:5000", None).await, StatusCode::OK);
+        assert_eq!(status(app(IpConfig::new()), "10.8.13.7:5000", None).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(app(IpConfig::new()), "192.0.2.1:5000", None).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(app(IpConfig::new()), "[fd00::1]:5000", None).await, StatusCode::OK);
+        assert_eq!(status(app(IpConfig::new()), "[::ffff:10.8.1.2]:5000", None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_peer_header_ignored() {
+        let forwarded = Some(("x-forwarded-for", "10.8.1.2"));
+        assert_eq!(status(app(IpConfig::new()), "192.0.2.1:5000", forwarded).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies() {
+        let config = || IpConfig::new().trust_proxies(["172.16.0.0/12", "192.0.2.10"]).unwrap();
+        // The client can prepend anything, only the hop added by our outermost proxy counts.
+        let forwarded = Some(("x-forwarded-for", "10.8.1.2, 198.51.100.7, 172.16.0.3"));
+        assert_eq!(status(app(config()), "172.16.0.2:5000", forwarded).await, StatusCode::FORBIDDEN);
+        let forwarded = Some(("x-forwarded-for", "198.51.100.7, 10.8.1.2, 192.0.2.10"));
+        assert_eq!(status(app(config()), "172.16.0.2:5000", forwarded).await, StatusCode::OK);
+        let forwarded = Some(("forwarded", r#"for="[fd00::7]:4711";proto=https, for=172.16.0.3"#));
+        assert_eq!(status(app(config().header(ProxyHeader::Forwarded)), "172.16.0.2:5000", forwarded).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_hop_ends_walk() {
+        let config = || IpConfig::new().trust_proxies(["172.16.0.0/12"]).unwrap();
+        // The client wrote `6.6.6.6`; our proxy could only say `unknown`.
+        let allowed = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(GuardService::new(config(), IpGuard::allow(["6.6.6.6"]).unwrap(), "denied").into_layer());
+        let forwarded = Some(("x-forwarded-for", "6.6.6.6, unknown"));
+        assert_eq!(status(allowed.clone(), "172.16.0.2:5000", forwarded).await, StatusCode::FORBIDDEN);
+        let forwarded = Some(("x-forwarded-for", "6.6.6.6, 172.16.0.3"));
+        assert_eq!(status(allowed, "172.16.0.2:5000", forwarded).await, StatusCode::OK);
+        let forwarded = Some(("forwarded", "for=10.8.1.2, for=_hidden"));
+        assert_eq!(status(app(config().header(ProxyHeader::Forwarded)), "172.16.0.2:5000", forwarded).await, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_parse_net() {
+        assert_eq!(parse_net("10.0.0.1").unwrap(), "10.0.0.1/32".parse().unwrap());
+        assert!(parse_net("10.0.0.0/33").is_err());
+    }
+}
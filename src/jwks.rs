@@ -0,0 +1,311 @@
+//! A cached JWKS that refreshes itself, for use as the [`KeySource`] of a [`JwtConfig`].
+//! Requires the `jwt` feature.
+//!
+//! [`JwtConfig`]: crate::jwt::JwtConfig
+use std::path::PathBuf;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+use hyper::client::HttpConnector;
+use hyper::client::connect::Connect;
+use hyper::{Client, Uri};
+use jsonwebtoken::jwk::{JwkSet, PublicKeyUse};
+use crate::jwt::{jwk_algorithm, Algorithm, DecodingKey, JwtError, KeySource, LocalKeys};
+
+/// Where a [`JwksStore`] loads the key set from.
+#[async_trait::async_trait]
+pub trait JwksSource: Send + Sync + 'static {
+    async fn fetch(&self) -> Result<JwkSet, JwtError>;
+}
+
+/// A JWKS document on disk.
+#[derive(Clone, Debug)]
+pub struct FileSource(pub PathBuf);
+#[async_trait::async_trait]
+impl JwksSource for FileSource {
+    async fn fetch(&self) -> Result<JwkSet, JwtError> {
+        Ok(serde_json::from_slice(&tokio::fs::read(&self.0).await?)?)
+    }
+}
+
+/// A JWKS endpoint. The default client only speaks plain HTTP,
+/// use [`HttpSource::with_client`] with a TLS connector for `https` URLs.
+#[derive(Clone, Debug)]
+pub struct HttpSource<C = HttpConnector> {
+    client: Client<C>,
+    uri: Uri,
+}
+impl HttpSource {
+    pub fn new(uri: Uri) -> Self {
+        Self { client: Client::new(), uri }
+    }
+}
+impl<C> HttpSource<C> {
+    pub fn with_client(client: Client<C>, uri: Uri) -> Self {
+        Self { client, uri }
+    }
+}
+#[async_trait::async_trait]
+impl<C> JwksSource for HttpSource<C>
+    where
+        C: Connect + Clone + Send + Sync + 'static, {
+    async fn fetch(&self) -> Result<JwkSet, JwtError> {
+        let io = |err: hyper::Error| JwtError::Io(std::io::Error::other(err));
+        let response = self.client.get(self.uri.clone()).await.map_err(io)?;
+        if !response.status().is_success() {
+            return Err(JwtError::Io(std::io::Error::other(format!("{} returned {}", self.uri, response.status()))));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await.map_err(io)?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+struct Cached {
+    keys: LocalKeys,
+    fetched_at: Option<Instant>,
+    attempted_at: Option<Instant>,
+}
+
+/// Caches the keys of a [`JwksSource`].
+///
+/// The set is fetched on first use, again once it is older than the refresh interval,
+/// and whenever a token names a `kid` the cache doesn't know. Fetches are at most one per
+/// `min_refresh_interval` so unknown `kid`s can't be used to hammer the source, and a
+/// failed fetch keeps the previous keys. A fetch taking longer than `fetch_timeout`
+/// counts as failed, so a slow source can't hold up the requests waiting on it.
+pub struct JwksStore<S = HttpSource> {
+    source: S,
+    cached: RwLock<Cached>,
+    refreshing: tokio::sync::Mutex<()>,
+    refresh_interval: Duration,
+    min_refresh_interval: Duration,
+    fetch_timeout: Duration,
+}
+impl<S: JwksSource> JwksStore<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            cached: RwLock::new(Cached { keys: LocalKeys::new(), fetched_at: None, attempted_at: None }),
+            refreshing: tokio::sync::Mutex::new(()),
+            refresh_interval: Duration::from_secs(600),
+            min_refresh_interval: Duration::from_secs(30),
+            fetch_timeout: Duration::from_secs(5),
+        }
+    }
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+    pub fn min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.min_refresh_interval = interval;
+        self
+    }
+    pub fn fetch_timeout(mut self, timeout: Duration) -> Self {
+        self.fetch_timeout = timeout;
+        self
+    }
+    /// Fetches the key set now, regardless of the refresh intervals.
+    pub async fn refresh(&self) -> Result<(), JwtError> {
+        let _refreshing = self.refreshing.lock().await;
+        self.fetch().await
+    }
+    // The cache is replaced whole, so a panic while it was locked can't leave it half updated.
+    fn read(&self) -> RwLockReadGuard<'_, Cached> {
+        self.cached.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, Cached> {
+        self.cached.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    async fn fetch(&self) -> Result<(), JwtError> {
+        self.write().attempted_at = Some(Instant::now());
+        let jwks = tokio::time::timeout(self.fetch_timeout, self.source.fetch()).await
+            .map_err(|_| JwtError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "fetching the key set timed out")))??;
+        // Keys that can't be used for verification are skipped rather than failing the whole set.
+        let keys = jwks.keys.iter()
+            .filter(|jwk| !matches!(jwk.common.public_key_use, Some(PublicKeyUse::Encryption)))
+            .filter_map(|jwk| Some((jwk.common.key_id.clone(), jwk_algorithm(jwk).ok()?, DecodingKey::from_jwk(jwk).ok()?)))
+            .fold(LocalKeys::new(), |keys, (kid, algorithm, key)| keys.with_key(kid, algorithm, key));
+        let mut cached = self.write();
+        cached.keys = keys;
+        cached.fetched_at = Some(Instant::now());
+        Ok(())
+    }
+    fn may_fetch(&self) -> bool {
+        let cached = self.read();
+        cached.attempted_at.is_none_or(|at| at.elapsed() >= self.min_refresh_interval)
+    }
+    fn lookup(&self, kid: Option<&str>, algorithm: Algorithm) -> (Vec<DecodingKey>, bool) {
+        let cached = self.read();
+        let stale = cached.fetched_at.is_none_or(|at| at.elapsed() >= self.refresh_interval);
+        (cached.keys.find(kid, algorithm), stale)
+    }
+}
+#[async_trait::async_trait]
+impl<S: JwksSource> KeySource for JwksStore<S> {
+    async fn keys(&self, kid: Option<&str>, algorithm: Algorithm) -> Vec<DecodingKey> {
+        let (found, stale) = self.lookup(kid, algorithm);
+        if !found.is_empty() && !stale {
+            return found;
+        }
+        if self.may_fetch() {
+            let _refreshing = self.refreshing.lock().await;
+            // Another request may have refreshed while we waited for the lock.
+            if self.may_fetch() {
+                let _ = self.fetch().await;
+            }
+        }
+        let (refreshed, _) = self.lookup(kid, algorithm);
+        if refreshed.is_empty() { found } else { refreshed }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use axum::{Json, Router};
+    use axum::extract::State;
+    use axum::routing::get;
+    use http::{header, Request, StatusCode};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use crate::jwt::{JwtClaims, JwtConfig};
+    use crate::jwt::tests::{now, sign};
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Jwks {
+        document: Arc<Mutex<Value>>,
+        hits: Arc<AtomicUsize>,
+    }
+    impl Jwks {
+        fn publish(&self, keys: &[(&str, &str)]) {
+            *self.document.lock().unwrap() = json!({"keys": keys.iter()
+                .map(|(kid, k)| json!({"kty": "oct", "kid": kid, "alg": "HS256", "k": k}))
+                .collect::<Vec<_>>()});
+        }
+        fn hits(&self) -> usize {
+            self.hits.load(Ordering::SeqCst)
+        }
+    }
+    async fn serve_jwks(State(jwks): State<Jwks>) -> Json<Value> {
+        jwks.hits.fetch_add(1, Ordering::SeqCst);
+        Json(jwks.document.lock().unwrap().clone())
+    }
+    fn spawn_jwks_server(jwks: Jwks) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/jwks.json", get(serve_jwks)).with_state(jwks);
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        addr
+    }
+    fn token(kid: &str, secret: &[u8]) -> String {
+        let header = Header { kid: Some(kid.into()), ..Header::default() };
+        sign(header, &EncodingKey::from_secret(secret), json!({"sub": "alice", "exp": now() + 60}))
+    }
+    async fn status(store: &Arc<JwksStore>, token: &str) -> StatusCode {
+        let app = axum::Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(GuardService::new(JwtConfig::new(store.clone()), JwtClaims::<Value>::any(), "invalid token").into_layer());
+        let req = Request::get("/")
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    // "b25lLXNlY3JldA" / "dHdvLXNlY3JldA" are base64url for "one-secret" / "two-secret".
+    #[tokio::test]
+    async fn test_refresh_on_unknown_kid() {
+        let jwks = Jwks::default();
+        jwks.publish(&[("one", "b25lLXNlY3JldA")]);
+        let addr = spawn_jwks_server(jwks.clone());
+        let uri: Uri = format!("http://{}/jwks.json", addr).parse().unwrap();
+        let store = Arc::new(JwksStore::new(HttpSource::new(uri)).min_refresh_interval(Duration::ZERO));
+
+        assert_eq!(status(&store, &token("one", b"one-secret")).await, StatusCode::OK);
+        assert_eq!(status(&store, &token("one", b"one-secret")).await, StatusCode::OK);
+        assert_eq!(jwks.hits(), 1);
+
+        jwks.publish(&[("one", "b25lLXNlY3JldA"), ("two", "dHdvLXNlY3JldA")]);
+        assert_eq!(status(&store, &token("two", b"two-secret")).await, StatusCode::OK);
+        assert_eq!(jwks.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_kid_rate_limited() {
+        let jwks = Jwks::default();
+        jwks.publish(&[("one", "b25lLXNlY3JldA")]);
+        let addr = spawn_jwks_server(jwks.clone());
+        let uri: Uri = format!("http://{}/jwks.json", addr).parse().unwrap();
+        let store = Arc::new(JwksStore::new(HttpSource::new(uri)).min_refresh_interval(Duration::from_secs(3600)));
+
+        assert_eq!(status(&store, &token("one", b"one-secret")).await, StatusCode::OK);
+        jwks.publish(&[("two", "dHdvLXNlY3JldA")]);
+        for _ in 0..3 {
+            assert_eq!(status(&store, &token("two", b"two-secret")).await, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(jwks.hits(), 1);
+
+        store.refresh().await.unwrap();
+        assert_eq!(status(&store, &token("two", b"two-secret")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_failed_refresh_keeps_keys() {
+        let jwks = Jwks::default();
+        jwks.publish(&[("one", "b25lLXNlY3JldA")]);
+        let addr = spawn_jwks_server(jwks.clone());
+        let uri: Uri = format!("http://{}/jwks.json", addr).parse().unwrap();
+        let store = Arc::new(JwksStore::new(HttpSource::new(uri))
+            .refresh_interval(Duration::ZERO)
+            .min_refresh_interval(Duration::ZERO));
+
+        assert_eq!(status(&store, &token("one", b"one-secret")).await, StatusCode::OK);
+        *jwks.document.lock().unwrap() = json!("not a key set");
+        assert_eq!(status(&store, &token("one", b"one-secret")).await, StatusCode::OK);
+        assert_eq!(jwks.hits(), 2);
+    }
+
+    struct Stalling {
+        file: FileSource,
+        stall: AtomicBool,
+    }
+    #[async_trait::async_trait]
+    impl JwksSource for Stalling {
+        async fn fetch(&self) -> Result<JwkSet, JwtError> {
+            if self.stall.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+            self.file.fetch().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_timeout() {
+        let path = std::env::temp_dir().join(format!("axum_guard_logic_jwks_timeout_{}.json", std::process::id()));
+        std::fs::write(&path, json!({"keys": [{"kty": "oct", "kid": "one", "k": "b25lLXNlY3JldA"}]}).to_string()).unwrap();
+        let source = Stalling { file: FileSource(path.clone()), stall: AtomicBool::new(false) };
+        let store = JwksStore::new(source)
+            .refresh_interval(Duration::ZERO)
+            .min_refresh_interval(Duration::ZERO)
+            .fetch_timeout(Duration::from_millis(50));
+        store.refresh().await.unwrap();
+        store.source.stall.store(true, Ordering::SeqCst);
+        assert!(matches!(store.refresh().await, Err(JwtError::Io(err)) if err.kind() == std::io::ErrorKind::TimedOut));
+        assert!(!store.keys(Some("one"), Algorithm::HS256).await.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_source() {
+        let path = std::env::temp_dir().join(format!("axum_guard_logic_jwks_store_{}.json", std::process::id()));
+        std::fs::write(&path, json!({"keys": [{"kty": "oct", "kid": "one", "k": "b25lLXNlY3JldA"}]}).to_string()).unwrap();
+        let store = JwksStore::new(FileSource(path.clone()));
+        assert!(!store.keys(Some("one"), Algorithm::HS256).await.is_empty());
+        assert!(store.keys(Some("one"), Algorithm::RS256).await.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
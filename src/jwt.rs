@@ -0,0 +1,429 @@
+//! JWT bearer tokens verified against locally configured keys. Requires the `jwt` feature.
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use axum_core::extract::FromRequestParts;
+use http::{header, HeaderValue, StatusCode};
+use http::request::Parts;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::Validation;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use crate::{authorization, Guard, GuardDenial};
+
+pub use jsonwebtoken::{Algorithm, DecodingKey};
+
+#[derive(Debug)]
+pub enum JwtError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Key(jsonwebtoken::errors::Error),
+    UnsupportedAlgorithm(String),
+}
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::Io(err) => write!(f, "could not read key set: {}", err),
+            JwtError::Json(err) => write!(f, "invalid key set: {}", err),
+            JwtError::Key(err) => write!(f, "invalid key: {}", err),
+            JwtError::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {}", alg),
+        }
+    }
+}
+impl std::error::Error for JwtError {}
+impl From<std::io::Error> for JwtError {
+    fn from(err: std::io::Error) -> Self {
+        JwtError::Io(err)
+    }
+}
+impl From<serde_json::Error> for JwtError {
+    fn from(err: serde_json::Error) -> Self {
+        JwtError::Json(err)
+    }
+}
+impl From<jsonwebtoken::errors::Error> for JwtError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        JwtError::Key(err)
+    }
+}
+
+/// Finds the keys a token may have been signed with from its header, most likely first.
+/// Each is tried until one verifies the token.
+#[async_trait::async_trait]
+pub trait KeySource: Send + Sync + 'static {
+    async fn keys(&self, kid: Option<&str>, algorithm: Algorithm) -> Vec<DecodingKey>;
+}
+
+#[derive(Clone)]
+struct LocalKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+#[async_trait::async_trait]
+impl<K: KeySource> KeySource for Arc<K> {
+    async fn keys(&self, kid: Option<&str>, algorithm: Algorithm) -> Vec<DecodingKey> {
+        K::keys(self, kid, algorithm).await
+    }
+}
+
+/// Keys configured up front, from secrets, PEM files or a JWKS document.
+///
+/// A key without a `kid` is a candidate for any token of its algorithm. A token
+/// naming a `kid` tries the key with that `kid` first; one without tries them all.
+#[derive(Clone, Default)]
+pub struct LocalKeys {
+    keys: Vec<LocalKey>,
+}
+impl LocalKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_key(mut self, kid: Option<String>, algorithm: Algorithm, key: DecodingKey) -> Self {
+        self.keys.push(LocalKey { kid, algorithm, key });
+        self
+    }
+    pub fn hs256(self, secret: &[u8]) -> Self {
+        self.with_key(None, Algorithm::HS256, DecodingKey::from_secret(secret))
+    }
+    pub fn rs256_pem(self, pem: &[u8]) -> Result<Self, JwtError> {
+        Ok(self.with_key(None, Algorithm::RS256, DecodingKey::from_rsa_pem(pem)?))
+    }
+    pub fn es256_pem(self, pem: &[u8]) -> Result<Self, JwtError> {
+        Ok(self.with_key(None, Algorithm::ES256, DecodingKey::from_ec_pem(pem)?))
+    }
+    pub fn from_jwks(jwks: &JwkSet) -> Result<Self, JwtError> {
+        jwks.keys.iter().try_fold(Self::new(), |keys, jwk| {
+            Ok(keys.with_key(jwk.common.key_id.clone(), jwk_algorithm(jwk)?, DecodingKey::from_jwk(jwk)?))
+        })
+    }
+    pub fn from_jwks_file(path: impl AsRef<Path>) -> Result<Self, JwtError> {
+        let jwks: JwkSet = serde_json::from_slice(&std::fs::read(path)?)?;
+        Self::from_jwks(&jwks)
+    }
+    pub(crate) fn find(&self, kid: Option<&str>, algorithm: Algorithm) -> Vec<DecodingKey> {
+        let candidates = self.keys.iter().filter(|key| key.algorithm == algorithm);
+        let (named, unnamed): (Vec<_>, Vec<_>) = match kid {
+            Some(kid) => candidates
+                .filter(|key| key.kid.as_deref().is_none_or(|key| key == kid))
+                .partition(|key| key.kid.is_some()),
+            None => (candidates.collect(), Vec::new()),
+        };
+        named.into_iter().chain(unnamed).map(|key| key.key.clone()).collect()
+    }
+}
+#[async_trait::async_trait]
+impl KeySource for LocalKeys {
+    async fn keys(&self, kid: Option<&str>, algorithm: Algorithm) -> Vec<DecodingKey> {
+        self.find(kid, algorithm)
+    }
+}
+
+/// The `alg` of a JWK, inferred from the key type when the document leaves it out.
+pub(crate) fn jwk_algorithm(jwk: &Jwk) -> Result<Algorithm, JwtError> {
+    if let Some(alg) = &jwk.common.key_algorithm {
+        let alg = alg.to_string();
+        return alg.parse().map_err(|_| JwtError::UnsupportedAlgorithm(alg));
+    }
+    Ok(match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Algorithm::RS256,
+        AlgorithmParameters::EllipticCurve(params) if params.curve == EllipticCurve::P384 => Algorithm::ES384,
+        AlgorithmParameters::EllipticCurve(_) => Algorithm::ES256,
+        AlgorithmParameters::OctetKeyPair(_) => Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+    })
+}
+
+/// The state passed to `GuardService::new`: where keys come from and which registered claims to enforce.
+/// `exp` is always required, `nbf` is checked when present.
+pub struct JwtConfig<K = LocalKeys> {
+    keys: Arc<K>,
+    issuers: Vec<String>,
+    audiences: Vec<String>,
+    leeway: u64,
+}
+impl<K> Clone for JwtConfig<K> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            issuers: self.issuers.clone(),
+            audiences: self.audiences.clone(),
+            leeway: self.leeway,
+        }
+    }
+}
+impl<K: KeySource> JwtConfig<K> {
+    pub fn new(keys: K) -> Self {
+        Self { keys: Arc::new(keys), issuers: Vec::new(), audiences: Vec::new(), leeway: 60 }
+    }
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuers.push(issuer.into());
+        self
+    }
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audiences.push(audience.into());
+        self
+    }
+    /// Clock skew tolerated on `exp` and `nbf`, in seconds.
+    pub fn leeway(mut self, leeway: u64) -> Self {
+        self.leeway = leeway;
+        self
+    }
+    pub fn keys(&self) -> &K {
+        &self.keys
+    }
+    async fn decode(&self, token: &str) -> Option<Value> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let keys = self.keys.keys(header.kid.as_deref(), header.alg).await;
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+        }
+        if self.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audiences);
+        }
+        keys.iter().find_map(|key| jsonwebtoken::decode::<Value>(token, key, &validation).ok()).map(|data| data.claims)
+    }
+}
+
+/// Extracted: the verified claims of the bearer token, if it had a valid one.
+/// Expected: a claims template the token has to cover.
+///
+/// Every field of the template must be present in the claims, strings equal. Arrays in
+/// the template are sets that must be contained in the claim, which may be an array or,
+/// for OAuth's `scope` and `scp`, a space separated string. On success the typed claims `C` are
+/// inserted into the request extensions.
+#[derive(Clone)]
+pub struct JwtClaims<C = Value> {
+    kind: Kind<C>,
+}
+#[derive(Clone)]
+enum Kind<C> {
+    Verified {
+        presented: bool,
+        claims: Option<(C, Value)>,
+    },
+    Template(Value),
+}
+impl<C> JwtClaims<C> {
+    /// Any valid token.
+    pub fn any() -> Self {
+        Self::template(Value::Object(Default::default()))
+    }
+    pub fn template(template: Value) -> Self {
+        Self { kind: Kind::Template(template) }
+    }
+    pub fn claims(&self) -> Option<&C> {
+        match &self.kind {
+            Kind::Verified { claims, .. } => claims.as_ref().map(|(claims, _)| claims),
+            Kind::Template(_) => None,
+        }
+    }
+}
+
+/// `scopes` is set below the `scope` and `scp` claims, whose strings are space separated lists.
+fn covers(claim: &Value, template: &Value, scopes: bool) -> bool {
+    match (claim, template) {
+        (Value::Object(claim), Value::Object(template)) => template.iter().all(|(key, template)| {
+            let scopes = key == "scope" || key == "scp";
+            claim.get(key).is_some_and(|claim| covers(claim, template, scopes))
+        }),
+        (_, Value::Array(template)) => template.iter().all(|template| covers(claim, template, scopes)),
+        (Value::Array(claim), template) => claim.iter().any(|claim| covers(claim, template, scopes)),
+        (Value::String(claim), Value::String(template)) if scopes => claim.split_whitespace().any(|part| part == template),
+        (claim, template) => claim == template,
+    }
+}
+
+impl<C> Guard for JwtClaims<C>
+    where
+        C: Clone + Send + Sync + 'static, {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match (&self.kind, &expected.kind) {
+            (Kind::Verified { claims: Some((_, raw)), .. }, Kind::Template(template)) => covers(raw, template, false),
+            _ => false,
+        }
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        let (denial, challenge) = match &self.kind {
+            Kind::Verified { claims: Some(_), .. } =>
+                (GuardDenial::status(StatusCode::FORBIDDEN), "Bearer error=\"insufficient_scope\""),
+            Kind::Verified { presented: true, .. } =>
+                (GuardDenial::default(), "Bearer error=\"invalid_token\""),
+            _ => (GuardDenial::default(), "Bearer"),
+        };
+        denial.with_header(header::WWW_AUTHENTICATE, HeaderValue::from_static(challenge))
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        if let Kind::Verified { claims: Some((claims, _)), .. } = self.kind {
+            parts.extensions.insert(claims);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<K, C> FromRequestParts<JwtConfig<K>> for JwtClaims<C>
+    where
+        K: KeySource,
+        C: DeserializeOwned + Send, {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &JwtConfig<K>) -> Result<Self, Self::Rejection> {
+        let Some(token) = authorization(parts, "Bearer") else {
+            return Ok(Self { kind: Kind::Verified { presented: false, claims: None } });
+        };
+        let claims = match state.decode(token).await {
+            Some(raw) => serde_json::from_value::<C>(raw.clone()).ok().map(|claims| (claims, raw)),
+            None => None,
+        };
+        Ok(Self { kind: Kind::Verified { presented: true, claims } })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use axum::body::Body;
+    use axum::{Extension, Router};
+    use axum::routing::get;
+    use http::Request;
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde::Deserialize;
+    use serde_json::json;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    #[derive(Clone, Deserialize)]
+    struct Claims {
+        sub: String,
+    }
+    async fn whoami(Extension(claims): Extension<Claims>) -> String {
+        claims.sub
+    }
+    pub(crate) fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+    pub(crate) fn sign(header: Header, key: &EncodingKey, claims: Value) -> String {
+        jsonwebtoken::encode(&header, &claims, key).unwrap()
+    }
+    fn app<K: KeySource>(config: JwtConfig<K>, expected: JwtClaims<Claims>) -> Router {
+        Router::new()
+            .route("/", get(whoami))
+            .layer(GuardService::new(config, expected, "invalid token").into_layer())
+    }
+    fn request(token: &str) -> Request<Body> {
+        Request::get("/").header(header::AUTHORIZATION, format!("Bearer {}", token)).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hs256_registered_claims() {
+        let config = || JwtConfig::new(LocalKeys::new().hs256(b"secret"))
+            .issuer("https://issuer.example")
+            .audience("api");
+        let key = EncodingKey::from_secret(b"secret");
+        let valid = json!({"sub": "alice", "iss": "https://issuer.example", "aud": "api", "exp": now() + 60});
+        let resp = app(config(), JwtClaims::any()).oneshot(request(&sign(Header::default(), &key, valid))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(&hyper::body::to_bytes(resp.into_body()).await.unwrap()[..], b"alice");
+
+        let invalid = [
+            json!({"sub": "alice", "iss": "https://issuer.example", "aud": "api", "exp": now() - 600}),
+            json!({"sub": "alice", "iss": "https://issuer.example", "aud": "api", "exp": now() + 60, "nbf": now() + 600}),
+            json!({"sub": "alice", "iss": "https://evil.example", "aud": "api", "exp": now() + 60}),
+            json!({"sub": "alice", "iss": "https://issuer.example", "aud": "other", "exp": now() + 60}),
+        ];
+        for claims in invalid {
+            let resp = app(config(), JwtClaims::any()).oneshot(request(&sign(Header::default(), &key, claims))).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer error=\"invalid_token\"");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_es256() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let keys = LocalKeys::new()
+            .with_key(None, Algorithm::ES256, DecodingKey::from_ec_der(pair.public_key().as_ref()));
+        let token = sign(
+            Header::new(Algorithm::ES256),
+            &EncodingKey::from_ec_der(pkcs8.as_ref()),
+            json!({"sub": "bob", "exp": now() + 60}),
+        );
+        let resp = app(JwtConfig::new(keys.clone()), JwtClaims::any()).oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // A token signed with HS256 must not be accepted by an ES256-only key set.
+        let token = sign(Header::default(), &EncodingKey::from_secret(b"secret"), json!({"sub": "bob", "exp": now() + 60}));
+        let resp = app(JwtConfig::new(keys), JwtClaims::any()).oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_jwks_file_and_kid() {
+        let path = std::env::temp_dir().join(format!("axum_guard_logic_jwks_{}.json", std::process::id()));
+        std::fs::write(&path, json!({"keys": [
+            {"kty": "oct", "kid": "old", "alg": "HS256", "k": "b2xkLXNlY3JldA"},
+            {"kty": "oct", "kid": "new", "alg": "HS256", "k": "bmV3LXNlY3JldA"},
+        ]}).to_string()).unwrap();
+        let keys = LocalKeys::from_jwks_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = Header { kid: Some("new".into()), ..Header::default() };
+        let token = sign(header.clone(), &EncodingKey::from_secret(b"new-secret"), json!({"sub": "carol", "exp": now() + 60}));
+        let resp = app(JwtConfig::new(keys.clone()), JwtClaims::any()).oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let token = sign(header, &EncodingKey::from_secret(b"old-secret"), json!({"sub": "carol", "exp": now() + 60}));
+        let resp = app(JwtConfig::new(keys), JwtClaims::any()).oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_claims_template() {
+        let config = || JwtConfig::new(LocalKeys::new().hs256(b"secret"));
+        let expected = || JwtClaims::template(json!({"scope": ["repo:read", "repo:write"], "org": {"plan": "pro"}}));
+        let key = EncodingKey::from_secret(b"secret");
+        let token = sign(Header::default(), &key, json!({
+            "sub": "dave", "exp": now() + 60, "scope": "repo:read repo:write admin", "org": {"plan": "pro", "id": 1}
+        }));
+        let resp = app(config(), expected()).oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let token = sign(Header::default(), &key, json!({"sub": "dave", "exp": now() + 60, "scope": "repo:read", "org": {"plan": "pro"}}));
+        let resp = app(config(), expected()).oneshot(request(&token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], "Bearer error=\"insufficient_scope\"");
+
+        let expected = || JwtClaims::template(json!({"role": "admin"}));
+        for role in ["not admin", "guest admin"] {
+            let token = sign(Header::default(), &key, json!({"sub": "eve", "exp": now() + 60, "role": role}));
+            let resp = app(config(), expected()).oneshot(request(&token)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        let token = sign(Header::default(), &key, json!({"sub": "eve", "exp": now() + 60, "role": "admin"}));
+        assert_eq!(app(config(), expected()).oneshot(request(&token)).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_local_keys_without_kid() {
+        let keys = LocalKeys::new().hs256(b"old-secret").hs256(b"new-secret");
+        for secret in [&b"old-secret"[..], b"new-secret"] {
+            let key = EncodingKey::from_secret(secret);
+            for kid in [None, Some("idp-key-7".to_string())] {
+                let token = sign(Header { kid, ..Header::default() }, &key, json!({"sub": "frank", "exp": now() + 60}));
+                let resp = app(JwtConfig::new(keys.clone()), JwtClaims::any()).oneshot(request(&token)).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+            }
+        }
+    }
+}
@@ -1,22 +1,97 @@
 use std::convert::Infallible;
 use std::task::{Poll, Context};
-use std::future::Future;
-use std::marker::{PhantomData, PhantomPinned};
-use std::pin::{Pin};
-use std::sync::{Arc, Mutex};
-use axum_core::body::BoxBody;
+use std::marker::PhantomData;
 use axum_core::extract::{FromRequestParts};
 use axum_core::response::{IntoResponse, Response};
 use futures_core::future::BoxFuture;
 use tower_service::Service;
-use http::{Request, StatusCode};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
 use http::request::Parts;
-use axum_core::BoxError;
-use futures_core::ready;
-use tower_layer::Layer;
+use tower_layer::{Layer, Stack};
+use crate::body::BufferBodyLayer;
+
+pub mod abac;
+pub mod api_key;
+pub mod basic;
+pub mod bearer;
+pub mod body;
+pub mod casbin;
+pub mod cedar;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod csrf;
+pub mod expr;
+pub mod ip;
+#[cfg(feature = "jwt")]
+pub mod jwks;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod owner;
+pub mod predicate;
+pub mod rate_limit;
+pub mod rbac;
+pub mod registry;
+#[cfg(feature = "config")]
+pub mod reload;
+pub mod schedule;
+pub mod scopes;
+pub mod session;
+pub mod tenant;
+pub mod webhook;
 
 pub trait Guard {
     fn check_guard(&self, expected:&Self) -> bool;
+    /// Status and headers to send instead of a bare `401` when `check_guard` fails.
+    fn denial(&self, _expected:&Self) -> GuardDenial {
+        GuardDenial::default()
+    }
+    /// Called with the extracted value when `check_guard` passes,
+    /// e.g. to hand a resolved principal to the handler through the extensions.
+    fn on_pass(self, _parts:&mut Parts) where Self:Sized {}
+}
+
+/// Shapes the rejection sent by `GuardServiceWrapper` when the guard tree fails.
+///
+/// Failing guards merge their denial into the request extensions; headers accumulate
+/// (so `a.or(b)` can send both `WWW-Authenticate` challenges) and the last status set wins.
+/// It is discarded as soon as the tree passes.
+#[derive(Clone, Debug, Default)]
+pub struct GuardDenial {
+    pub status: Option<StatusCode>,
+    pub headers: HeaderMap,
+}
+impl GuardDenial {
+    pub fn status(status:StatusCode) -> Self {
+        Self{ status:Some(status), headers:HeaderMap::new() }
+    }
+    pub fn with_header(mut self,name:HeaderName,value:HeaderValue) -> Self {
+        self.headers.append(name,value);
+        self
+    }
+    pub fn merge_into(self,parts:&mut Parts) {
+        match parts.extensions.get_mut::<GuardDenial>() {
+            Some(denial) => {
+                if self.status.is_some() {
+                    denial.status = self.status;
+                }
+                append_headers(&mut denial.headers,&self.headers);
+            },
+            None => {
+                parts.extensions.insert(self);
+            }
+        }
+    }
+    fn into_response(self,err_msg:String) -> Response {
+        let mut response = (self.status.unwrap_or(StatusCode::UNAUTHORIZED),err_msg).into_response();
+        append_headers(response.headers_mut(),&self.headers);
+        response
+    }
+}
+// `HeaderMap::extend` replaces existing values, denials need to keep every challenge.
+fn append_headers(into:&mut HeaderMap,from:&HeaderMap) {
+    for (name,value) in from {
+        into.append(name.clone(),value.clone());
+    }
 }
 
 pub trait GuardServiceExt : Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Clone + Send {
@@ -42,29 +117,53 @@ pub trait GuardServiceExt : Service<Parts, Response=GuardServiceResponse,Error=(
             right: other,
         }
     }
+    /// Passes when `self` fails and fails with `err_msg` when it passes.
+    fn not(self,err_msg:&'static str) -> NotGuardService<Self>
+        where
+            <Self as Service<Parts>>::Future: Send {
+        NotGuardService{
+            inner:self,
+            err_msg,
+        }
+    }
+    /// Erases the type of the guard tree, e.g. to build it at runtime.
+    fn boxed_guard(self) -> BoxGuardService
+        where
+            Self: Sync + 'static,
+            <Self as Service<Parts>>::Future: Send + 'static {
+        BoxGuardService::new(self)
+    }
     fn into_layer<ReqBody>(self) -> GuardLayer<Self,ReqBody> {
         GuardLayer::with(self)
     }
+    /// Like `into_layer`, but buffers up to `limit` bytes of the request body first
+    /// so guards can read it from the `BufferedBody` extension.
+    fn into_buffered_layer<ReqBody>(self,limit:usize) -> Stack<GuardLayer<Self,ReqBody>,BufferBodyLayer> {
+        Stack::new(GuardLayer::with(self),BufferBodyLayer::new(limit))
+    }
 }
 impl<T> GuardServiceExt for T
     where
         T: Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Clone + Send {}
 
-pub struct GuardLayer<GuardService,ReqBody>
+pub struct GuardLayer<GuardService,ReqBody,ResGuard=()>
     where
         GuardService:Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)>
         + Send + Clone + 'static {
     guard_service:GuardService,
+    response_guard:ResGuard,
     _marker:PhantomData<ReqBody>
 }
 
-impl<GuardService,ReqBody> Clone for GuardLayer<GuardService,ReqBody>
+impl<GuardService,ReqBody,ResGuard> Clone for GuardLayer<GuardService,ReqBody,ResGuard>
     where
         GuardService:Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)>
-        + Send + Clone + 'static {
+        + Send + Clone + 'static,
+        ResGuard:Clone {
     fn clone(&self) -> Self {
         Self{
             guard_service: self.guard_service.clone(),
+            response_guard: self.response_guard.clone(),
             _marker: PhantomData,
         }
     }
@@ -74,60 +173,75 @@ impl<GuardService,ReqBody> GuardLayer<GuardService,ReqBody>
         GuardService:Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)>
         + Send + Clone + 'static {
     pub fn with(guard:GuardService) -> Self {
-        Self{ guard_service:guard, _marker:PhantomData }
+        Self{ guard_service:guard, response_guard:(), _marker:PhantomData }
     }
 }
-impl<S,GuardService,ReqBody> Layer<S> for GuardLayer<GuardService,ReqBody>
+impl<GuardService,ReqBody,ResGuard> GuardLayer<GuardService,ReqBody,ResGuard>
     where
-        S:Service<Request<ReqBody>> + Clone,
         GuardService:Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)>
         + Send + Clone + 'static {
-    type Service = GuardServiceWrapper<S,GuardService,ReqBody>;
+    /// Runs `response_guard` against the handler's response before it is returned.
+    pub fn on_response<Other:ResponseGuard>(self,response_guard:Other) -> GuardLayer<GuardService,ReqBody,Other> {
+        GuardLayer{ guard_service:self.guard_service, response_guard, _marker:PhantomData }
+    }
+}
+impl<S,GuardService,ReqBody,ResGuard> Layer<S> for GuardLayer<GuardService,ReqBody,ResGuard>
+    where
+        S:Service<Request<ReqBody>> + Clone,
+        GuardService:Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)>
+        + Send + Clone + 'static,
+        ResGuard:Clone {
+    type Service = GuardServiceWrapper<S,GuardService,ReqBody,ResGuard>;
 
     fn layer(&self, inner: S) -> Self::Service {
         GuardServiceWrapper{
             inner,
             guard_service:self.guard_service.clone(),
+            response_guard:self.response_guard.clone(),
             _marker:PhantomData
         }
     }
 }
-pub struct GuardServiceWrapper<S,GuardService,ReqBody>
+pub struct GuardServiceWrapper<S,GuardService,ReqBody,ResGuard=()>
     where
         S:Service<Request<ReqBody>> + Clone,
         GuardService:Service<Parts, Response=GuardServiceResponse>
         + Send + Clone + 'static {
     inner:S,
     guard_service:GuardService,
+    response_guard:ResGuard,
     _marker:PhantomData<ReqBody>
 }
-impl<S,GuardService,ReqBody> Clone for GuardServiceWrapper<S,GuardService,ReqBody>
+impl<S,GuardService,ReqBody,ResGuard> Clone for GuardServiceWrapper<S,GuardService,ReqBody,ResGuard>
     where
         S:Service<Request<ReqBody>> + Clone,
         GuardService:Service<Parts, Response=GuardServiceResponse>
-        + Send + Clone + 'static {
+        + Send + Clone + 'static,
+        ResGuard:Clone {
     fn clone(&self) -> Self {
         Self{
             inner: self.inner.clone(),
             guard_service: self.guard_service.clone(),
+            response_guard: self.response_guard.clone(),
             _marker: PhantomData
         }
     }
 }
-impl<S,ReqBody,GuardService> Service<Request<ReqBody>> for
-GuardServiceWrapper<S,GuardService,ReqBody>
+impl<S,ReqBody,GuardService,ResGuard> Service<Request<ReqBody>> for
+GuardServiceWrapper<S,GuardService,ReqBody,ResGuard>
     where
         ReqBody:Send +'static,
         S:Service<Request<ReqBody>, Response = Response, Error = Infallible> + Send + Clone + 'static,
         <S as Service<Request<ReqBody>>>::Future: Send,
         <GuardService as Service<Parts>>::Future: Send,
         GuardService:Service<Parts, Response=GuardServiceResponse, Error = (StatusCode,String)>
-        + Send + Clone + 'static{
+        + Send + Clone + 'static,
+        ResGuard:ResponseGuard{
     type Response = Response;
     type Error = Infallible;
     type Future = BoxFuture<'static,Result<Response,Infallible>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
@@ -135,14 +249,21 @@ GuardServiceWrapper<S,GuardService,ReqBody>
         let (parts,body) = req.into_parts();
         let f = self.guard_service.call(parts);
         let mut inner = self.inner.clone();
+        let response_guard = self.response_guard.clone();
         Box::pin(async move {
             match f.await {
-                Ok(GuardServiceResponse(result,parts)) => {
+                Ok(GuardServiceResponse(result,mut parts)) => {
+                    let denial = parts.extensions.remove::<GuardDenial>().unwrap_or_default();
                     if result.0 {
-                        inner.call(Request::from_parts(parts,body))
-                            .await
+                        let captured = response_guard.capture(&parts);
+                        let response = inner.call(Request::from_parts(parts,body))
+                            .await?;
+                        match response_guard.check_response(captured,&response) {
+                            Ok(()) => Ok(response),
+                            Err(status) => Ok(status.into_response()),
+                        }
                     } else {
-                        Ok((StatusCode::UNAUTHORIZED,result.1.unwrap_or_default()).into_response())
+                        Ok(denial.into_response(result.1.unwrap_or_default()))
                     }
                 },
                 Err(status) => {
@@ -152,6 +273,44 @@ GuardServiceWrapper<S,GuardService,ReqBody>
         })
     }
 }
+
+/// A check that runs after the handler, for rules that depend on what the handler loaded.
+///
+/// `capture` is called with the request parts once the request guards have passed,
+/// `check_response` with whatever it captured and the handler's response.
+/// Returning an error replaces the response.
+pub trait ResponseGuard : Clone + Send + Sync + 'static {
+    type Captured: Send + 'static;
+    fn capture(&self, parts:&Parts) -> Self::Captured;
+    fn check_response(&self, captured:Self::Captured, response:&Response) -> Result<(),(StatusCode,String)>;
+}
+impl ResponseGuard for () {
+    type Captured = ();
+    fn capture(&self, _parts:&Parts) {}
+    fn check_response(&self, _captured:(), _response:&Response) -> Result<(),(StatusCode,String)> {
+        Ok(())
+    }
+}
+/// The credentials of an `Authorization: <scheme> <credentials>` header, scheme matched case-insensitively.
+pub(crate) fn authorization<'a>(parts:&'a Parts,scheme:&str) -> Option<&'a str> {
+    let value = parts.headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (found,credentials) = value.split_once(' ')?;
+    if found.eq_ignore_ascii_case(scheme) {
+        Some(credentials.trim())
+    } else {
+        None
+    }
+}
+/// The value of the cookie called `name`, if the request carries one.
+pub(crate) fn cookie(parts:&Parts,name:&str) -> Option<String> {
+    parts.headers.get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key,_)| *key == name)
+        .map(|(_,value)| value.trim_matches('"').to_string())
+}
 /*
 This panics when the inner future returns pending... how to fix?
 #[pin_project::pin_project]
@@ -223,7 +382,7 @@ impl<State,G> Service<Parts> for GuardService<State,G>
     type Error = (StatusCode,String);
     type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
@@ -234,7 +393,13 @@ impl<State,G> Service<Parts> for GuardService<State,G>
         Box::pin(async move {
             let result = match G::from_request_parts(&mut req, &state).await {
                 Ok(guard) => {
-                    guard.check_guard(&expected)
+                    let result = guard.check_guard(&expected);
+                    if result {
+                        guard.on_pass(&mut req);
+                    } else {
+                        guard.denial(&expected).merge_into(&mut req);
+                    }
+                    result
                 },
                 Err(status) => {
                     return Err(status);
@@ -248,6 +413,75 @@ impl<State,G> Service<Parts> for GuardService<State,G>
     }
 }
 
+/// What a `guard_fn` closure returns: `true` to pass, or a reason to fail with.
+pub trait GuardOutcome {
+    fn into_outcome(self) -> Result<(),Option<String>>;
+}
+impl GuardOutcome for bool {
+    fn into_outcome(self) -> Result<(),Option<String>> {
+        if self { Ok(()) } else { Err(None) }
+    }
+}
+impl GuardOutcome for Result<(),String> {
+    fn into_outcome(self) -> Result<(),Option<String>> {
+        self.map_err(Some)
+    }
+}
+impl GuardOutcome for Result<(),&'static str> {
+    fn into_outcome(self) -> Result<(),Option<String>> {
+        self.map_err(|reason| Some(String::from(reason)))
+    }
+}
+
+/// A one-off guard from a closure, see `guard_fn`.
+#[derive(Clone)]
+pub struct GuardFn<State,F> {
+    state:State,
+    f:F,
+    err_msg:&'static str,
+}
+/// Builds a guard service from `f(&parts, &state)`.
+///
+/// The future `f` returns can't borrow its arguments, so read what it needs first:
+/// `guard_fn(db, |parts, db| { let db = db.clone(); let id = ...; async move { db.allowed(id).await } })`.
+/// Failing with `false` uses the message set by `GuardFn::err_msg`, `"forbidden"` by default.
+pub fn guard_fn<State,F,Fut>(state:State,f:F) -> GuardFn<State,F>
+    where
+        F:Fn(&Parts,&State) -> Fut,
+        Fut:std::future::Future {
+    GuardFn{ state, f, err_msg:"forbidden" }
+}
+impl<State,F> GuardFn<State,F> {
+    pub fn err_msg(mut self,err_msg:&'static str) -> Self {
+        self.err_msg = err_msg;
+        self
+    }
+}
+impl<State,F,Fut> Service<Parts> for GuardFn<State,F>
+    where
+        F:Fn(&Parts,&State) -> Fut,
+        Fut:std::future::Future + Send + 'static,
+        Fut::Output:GuardOutcome {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode,String);
+    type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, parts: Parts) -> Self::Future {
+        let f = (self.f)(&parts,&self.state);
+        let err_msg = self.err_msg;
+        Box::pin(async move {
+            match f.await.into_outcome() {
+                Ok(()) => Ok(GuardServiceResponse((true,None),parts)),
+                Err(reason) => Ok(GuardServiceResponse((false,Some(reason.unwrap_or_else(|| String::from(err_msg)))),parts)),
+            }
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct AndGuardService<S1,S2>
     where
@@ -278,7 +512,7 @@ impl<S1,S2> Service<Parts> for AndGuardService<S1,S2>
     type Error = (StatusCode,String);
     type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
@@ -327,7 +561,7 @@ impl<S1,S2> Service<Parts> for OrGuardService<S1,S2>
     type Error = (StatusCode,String);
     type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
@@ -340,13 +574,156 @@ impl<S1,S2> Service<Parts> for OrGuardService<S1,S2>
             if result.0{
                 Ok(GuardServiceResponse((true,None), parts))
             } else {
-                right.call(parts).await
+                let GuardServiceResponse(result,mut parts) =
+                    right.call(parts).await?;
+                if result.0 {
+                    parts.extensions.remove::<GuardDenial>();
+                }
+                Ok(GuardServiceResponse(result,parts))
+            }
+        })
+
+    }
+}
+
+#[derive(Clone)]
+pub struct NotGuardService<S>
+    where
+        S: Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Send + Clone + 'static,
+        <S as Service<Parts>>::Future: Send,{
+    inner:S,
+    err_msg:&'static str,
+}
+impl<S> NotGuardService<S>
+    where
+        S: Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Send + Clone + 'static,
+        <S as Service<Parts>>::Future: Send, {
+    pub fn new(inner:S,err_msg:&'static str) -> Self{
+        Self{ inner, err_msg }
+    }
+}
+impl<S> Service<Parts> for NotGuardService<S>
+    where
+        S: Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Send + Clone + 'static,
+        <S as Service<Parts>>::Future: Send, {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode,String);
+    type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let err_msg = self.err_msg;
+        Box::pin(async move {
+            // The inner guard's denial describes the opposite outcome, keep only what came before it.
+            let before = parts.extensions.remove::<GuardDenial>();
+            let GuardServiceResponse(result,mut parts) =
+                inner.call(parts).await?;
+            parts.extensions.remove::<GuardDenial>();
+            if let Some(before) = before {
+                parts.extensions.insert(before);
+            }
+            if result.0 {
+                Ok(GuardServiceResponse((false,Some(String::from(err_msg))), parts))
+            } else {
+                Ok(GuardServiceResponse((true,None), parts))
             }
         })
+    }
+}
 
+/// Passes when at least `k` of its guards pass, stopping as soon as the outcome is known.
+#[derive(Clone)]
+pub struct ThresholdGuardService {
+    k:usize,
+    guards:Vec<BoxGuardService>,
+}
+impl ThresholdGuardService {
+    /// # Panics
+    /// If `k` is 0, which would pass without running a guard, or more than there are guards,
+    /// which could never pass.
+    pub fn new(k:usize,guards:Vec<BoxGuardService>) -> Self{
+        assert!(k >= 1 && k <= guards.len(), "k must be between 1 and the number of guards, got {} of {}", k, guards.len());
+        Self{ k, guards }
     }
 }
+impl Service<Parts> for ThresholdGuardService {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode,String);
+    type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
 
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let k = self.k;
+        let guards = self.guards.clone();
+        Box::pin(async move {
+            let mut passed = 0;
+            let mut err_msg = None;
+            for (i,mut guard) in guards.iter().cloned().enumerate() {
+                if passed >= k || passed + (guards.len() - i) < k {
+                    break;
+                }
+                let GuardServiceResponse(result,next) = guard.call(parts).await?;
+                parts = next;
+                if result.0 {
+                    passed += 1;
+                } else {
+                    err_msg = result.1;
+                }
+            }
+            if passed >= k {
+                parts.extensions.remove::<GuardDenial>();
+                Ok(GuardServiceResponse((true,None), parts))
+            } else {
+                Ok(GuardServiceResponse((false,err_msg), parts))
+            }
+        })
+    }
+}
+
+trait ErasedGuardService: Send + Sync {
+    fn call_boxed(&self, parts:Parts) -> BoxFuture<'static,Result<GuardServiceResponse,(StatusCode,String)>>;
+}
+impl<S> ErasedGuardService for S
+    where
+        S: Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Send + Sync + Clone + 'static,
+        <S as Service<Parts>>::Future: Send + 'static, {
+    fn call_boxed(&self, parts:Parts) -> BoxFuture<'static,Result<GuardServiceResponse,(StatusCode,String)>> {
+        let mut service = self.clone();
+        Box::pin(async move { service.call(parts).await })
+    }
+}
+
+/// A guard service of any type, cheap to clone.
+#[derive(Clone)]
+pub struct BoxGuardService(std::sync::Arc<dyn ErasedGuardService>);
+impl BoxGuardService {
+    pub fn new<S>(service:S) -> Self
+        where
+            S: Service<Parts, Response=GuardServiceResponse,Error=(StatusCode,String)> + Send + Sync + Clone + 'static,
+            <S as Service<Parts>>::Future: Send + 'static, {
+        Self(std::sync::Arc::new(service))
+    }
+}
+impl Service<Parts> for BoxGuardService {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode,String);
+    type Future = BoxFuture<'static,Result<Self::Response,Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, parts: Parts) -> Self::Future {
+        self.0.call_boxed(parts)
+    }
+}
 
 pub struct GuardServiceResponse( (bool,Option<String>), Parts);
 
@@ -356,23 +733,25 @@ pub struct GuardServiceResponse( (bool,Option<String>), Parts);
 pub mod tests {
     use tokio::time::{sleep, Duration};
     use axum::body::Body;
-    use axum::error_handling::{HandleError, HandleErrorLayer};
-    use axum::handler::Handler;
     use axum::Router;
     use axum::routing::get;
+    use axum_core::body::BoxBody;
     use http::{HeaderValue, Request, StatusCode};
     use tower::util::ServiceExt;
-    use axum::BoxError;
-    use axum::extract::State;
     use axum::middleware::Next;
     use axum_core::extract::FromRequestParts;
-    use tower::{service_fn, ServiceBuilder};
 
     #[derive(Clone, Debug, PartialEq)]
     pub struct ArbitraryData {
         data: String,
     }
 
+    impl ArbitraryData {
+        pub fn new(data: &str) -> Self {
+            Self { data: data.into() }
+        }
+    }
+
     impl Guard for ArbitraryData {
         fn check_guard(&self, expected: &Self) -> bool {
             *self == *expected
@@ -388,7 +767,7 @@ pub mod tests {
                 data: parts.headers.get(state.data.clone())
                     .ok_or((StatusCode::INTERNAL_SERVER_ERROR,"error".into()))?
                     .to_str()
-                    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR,"error".into()))?
+                    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR,"error".into()))?
                     .to_string()
             })
         }
@@ -530,6 +909,26 @@ pub mod tests {
         )
     }
     #[tokio::test]
+    async fn test_guard_fn() {
+        let allowed = vec!["alice".to_string()];
+        let app = Router::new()
+            .route("/", get(ok))
+            .layer(
+                guard_fn(allowed,|parts:&Parts,allowed:&Vec<String>| {
+                    let user = parts.headers.get("x-user").and_then(|user| user.to_str().ok()).unwrap_or_default();
+                    let known = allowed.iter().any(|allowed| allowed == user);
+                    async move { if known { Ok(()) } else { Err("unknown user") } }
+                })
+                .or(guard_fn((),|parts:&Parts,_:&()| std::future::ready(parts.uri.path() == "/health")).err_msg("nope"))
+                .into_layer()
+            );
+        let req = |user:&str| Request::builder().header("x-user",user).body(BoxBody::default()).unwrap();
+        assert_eq!(app.clone().oneshot(req("alice")).await.unwrap().status(),StatusCode::OK);
+        let resp = app.oneshot(req("mallory")).await.unwrap();
+        assert_eq!(resp.status(),StatusCode::UNAUTHORIZED);
+        assert_eq!(&hyper::body::to_bytes(resp.into_body()).await.unwrap()[..],b"nope");
+    }
+    #[tokio::test]
     async fn test_layer() {
         let req = Request::builder()
             .header("data","data")
@@ -727,6 +1126,47 @@ pub mod tests {
         let resp = app.oneshot(req).await.unwrap();
         assert_eq!(resp.status(),StatusCode::OK)
     }
+    #[derive(Clone)]
+    struct Owner(String);
+    #[derive(Clone)]
+    struct OwnerMatchesUser;
+    impl ResponseGuard for OwnerMatchesUser {
+        type Captured = Option<String>;
+        fn capture(&self, parts: &Parts) -> Self::Captured {
+            parts.headers.get("user").and_then(|user| user.to_str().ok()).map(String::from)
+        }
+        fn check_response(&self, captured: Self::Captured, response: &Response) -> Result<(), (StatusCode, String)> {
+            match (captured, response.extensions().get::<Owner>()) {
+                (Some(user), Some(Owner(owner))) if user == *owner => Ok(()),
+                _ => Err((StatusCode::FORBIDDEN, "not the owner".into())),
+            }
+        }
+    }
+    async fn owned_by_alice() -> Response {
+        let mut response = StatusCode::OK.into_response();
+        response.extensions_mut().insert(Owner("alice".into()));
+        response
+    }
+    #[tokio::test]
+    async fn test_response_guard() {
+        let data = ArbitraryData { data: "data".into() };
+        let app = Router::new()
+            .route("/", get(owned_by_alice))
+            .layer(
+                GuardService::new(data.clone(), data.clone(),"err")
+                    .into_layer()
+                    .on_response(OwnerMatchesUser)
+            );
+        let req = |user: &'static str| Request::builder()
+            .header("data","data")
+            .header("user",user)
+            .body(BoxBody::default())
+            .unwrap();
+        let resp = app.clone().oneshot(req("alice")).await.unwrap();
+        assert_eq!(resp.status(),StatusCode::OK);
+        let resp = app.oneshot(req("mallory")).await.unwrap();
+        assert_eq!(resp.status(),StatusCode::FORBIDDEN);
+    }
     #[tokio::test]
     async fn test_happy_with_layered_polls() {
         let app = Router::new()
@@ -752,4 +1192,17 @@ pub mod tests {
         assert_eq!(response.status(), StatusCode::OK);
     }
 
+    fn threshold_guards(n:usize) -> Vec<BoxGuardService> {
+        (0..n).map(|_| GuardService::new(ArbitraryData{data:"x".into()},ArbitraryData{data:"x".into()},"err").boxed_guard()).collect()
+    }
+    #[test]
+    #[should_panic(expected = "k must be between 1")]
+    fn test_threshold_zero_rejected() {
+        ThresholdGuardService::new(0,threshold_guards(2));
+    }
+    #[test]
+    #[should_panic(expected = "k must be between 1")]
+    fn test_threshold_above_guards_rejected() {
+        ThresholdGuardService::new(3,threshold_guards(2));
+    }
 }
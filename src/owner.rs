@@ -0,0 +1,134 @@
+//! Resource ownership: `/users/:id/...` only for the user whose id is `:id`.
+use std::collections::HashMap;
+use std::sync::Arc;
+use axum::extract::RawPathParams;
+use axum_core::extract::FromRequestParts;
+use http::StatusCode;
+use http::request::Parts;
+use crate::{Guard, GuardDenial};
+
+type PrincipalField<P> = Arc<dyn Fn(&P) -> String + Send + Sync>;
+type PrincipalCheck<P> = Arc<dyn Fn(&P) -> bool + Send + Sync>;
+
+/// Extracted: the path parameters and the principal `P`, any extractor for the
+/// `GuardService` state such as `Extension<Claims>` put there by an earlier guard.
+/// Expected: which parameter must equal which field of the principal, and optionally
+/// who may bypass the check.
+///
+/// The guard must run after routing, as a route layer, for the path parameters to exist.
+/// Without a principal it fails with `401`, with the wrong one with `403`.
+pub struct Owner<P> {
+    principal: Option<P>,
+    params: HashMap<String, String>,
+    param: &'static str,
+    field: Option<PrincipalField<P>>,
+    bypass: Option<PrincipalCheck<P>>,
+}
+impl<P> Owner<P> {
+    /// The path parameter `param` must equal `field(principal)`.
+    pub fn param(param: &'static str, field: impl Fn(&P) -> String + Send + Sync + 'static) -> Self {
+        Self { principal: None, params: HashMap::new(), param, field: Some(Arc::new(field)), bypass: None }
+    }
+    /// Principals for which `check` holds, e.g. admins, pass whatever the path says.
+    pub fn or_if(mut self, check: impl Fn(&P) -> bool + Send + Sync + 'static) -> Self {
+        self.bypass = Some(Arc::new(check));
+        self
+    }
+    pub fn principal(&self) -> Option<&P> {
+        self.principal.as_ref()
+    }
+}
+impl<P: Clone> Clone for Owner<P> {
+    fn clone(&self) -> Self {
+        Self {
+            principal: self.principal.clone(),
+            params: self.params.clone(),
+            param: self.param,
+            field: self.field.clone(),
+            bypass: self.bypass.clone(),
+        }
+    }
+}
+impl<P> Guard for Owner<P> {
+    fn check_guard(&self, expected: &Self) -> bool {
+        let Some(principal) = &self.principal else {
+            return false;
+        };
+        if expected.bypass.as_ref().is_some_and(|bypass| bypass(principal)) {
+            return true;
+        }
+        match (self.params.get(expected.param), &expected.field) {
+            (Some(value), Some(field)) => *value == field(principal),
+            _ => false,
+        }
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        if self.principal.is_none() {
+            GuardDenial::default()
+        } else {
+            GuardDenial::status(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, P> FromRequestParts<S> for Owner<P>
+    where
+        S: Send + Sync,
+        P: FromRequestParts<S> + Send, {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let params = match RawPathParams::from_request_parts(parts, state).await {
+            Ok(params) => params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            Err(_) => HashMap::new(),
+        };
+        let principal = P::from_request_parts(parts, state).await.ok();
+        Ok(Self { principal, params, param: "", field: None, bypass: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    #[derive(Clone)]
+    struct User {
+        id: u64,
+        admin: bool,
+    }
+    #[async_trait::async_trait]
+    impl FromRequestParts<()> for User {
+        type Rejection = StatusCode;
+
+        async fn from_request_parts(parts: &mut Parts, _state: &()) -> Result<Self, Self::Rejection> {
+            let id = parts.headers.get("x-user")
+                .and_then(|id| id.to_str().ok()?.parse().ok())
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            Ok(User { id, admin: parts.headers.contains_key("x-admin") })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_owner_or_admin() {
+        let expected = Owner::param("id", |user: &User| user.id.to_string()).or_if(|user| user.admin);
+        let app = Router::new()
+            .route("/users/:id/orders", get(|| async { StatusCode::OK }))
+            .route_layer(GuardService::new((), expected, "not yours").into_layer());
+        let req = |path: &str, headers: &[(&str, &str)]| headers.iter()
+            .fold(Request::get(path), |req, (name, value)| req.header(*name, *value))
+            .body(Body::empty())
+            .unwrap();
+        let status = |req| async { app.clone().oneshot(req).await.unwrap().status() };
+        assert_eq!(status(req("/users/7/orders", &[("x-user", "7")])).await, StatusCode::OK);
+        assert_eq!(status(req("/users/8/orders", &[("x-user", "7")])).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(req("/users/8/orders", &[("x-user", "7"), ("x-admin", "")])).await, StatusCode::OK);
+        assert_eq!(status(req("/users/8/orders", &[])).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,213 @@
+//! Guards on the request itself: method, path, host, headers and query parameters.
+//!
+//! They need no state or extractor and compose with credential guards through
+//! `GuardServiceExt`, e.g. `Method::any_of([Method::GET]).or(admin)`.
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use futures_core::future::BoxFuture;
+use http::{Method, StatusCode};
+use http::request::Parts;
+use regex::Regex;
+use tower_service::Service;
+use crate::{GuardDenial, GuardServiceResponse};
+
+#[derive(Clone, Debug)]
+enum Check {
+    Methods(Vec<Method>),
+    Path(Arc<Regex>),
+    Host(String),
+    SubdomainOf(String),
+    HeaderPresent(String),
+    HeaderEquals(String, String),
+    HeaderRegex(String, Arc<Regex>),
+    QueryEquals(String, String),
+}
+impl Check {
+    fn test(&self, parts: &Parts) -> bool {
+        let header = |name: &str| parts.headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).collect::<Vec<_>>();
+        match self {
+            Check::Methods(methods) => methods.contains(&parts.method),
+            Check::Path(re) => re.is_match(parts.uri.path()),
+            Check::Host(expected) => host(parts).is_some_and(|host| host == *expected),
+            Check::SubdomainOf(domain) => host(parts)
+                .and_then(|host| host.strip_suffix(domain.as_str()).map(|sub| sub.len() > 1 && sub.ends_with('.')))
+                .unwrap_or(false),
+            Check::HeaderPresent(name) => parts.headers.contains_key(name.as_str()),
+            Check::HeaderEquals(name, expected) => header(name).contains(&expected.as_str()),
+            Check::HeaderRegex(name, re) => header(name).iter().any(|value| re.is_match(value)),
+            Check::QueryEquals(name, expected) => form_urlencoded::parse(parts.uri.query().unwrap_or_default().as_bytes())
+                .any(|(key, value)| key == name.as_str() && value == expected.as_str()),
+        }
+    }
+}
+
+/// The request host without its port, lowercased, from the URI or the `Host` header.
+pub(crate) fn host(parts: &Parts) -> Option<String> {
+    let host = match parts.uri.host() {
+        Some(host) => host,
+        None => {
+            let header = parts.headers.get(http::header::HOST)?.to_str().ok()?;
+            match header.find(']') {
+                Some(end) if header.starts_with('[') => &header[..=end],
+                _ => header.split(':').next().unwrap_or(header),
+            }
+        }
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// `*` matches within a path segment, `**` across segments.
+fn glob(pattern: &str) -> Regex {
+    let pattern = regex::escape(pattern).replace(r"\*\*", ".*").replace(r"\*", "[^/]*");
+    Regex::new(&format!("^{}$", pattern)).expect("an escaped glob is a valid regex")
+}
+
+/// A guard service testing a property of the request.
+///
+/// Failing leaves the rejection status to the rest of the tree (`401` on its own)
+/// unless [`Predicate::status`] sets one.
+#[derive(Clone, Debug)]
+pub struct Predicate {
+    check: Check,
+    err_msg: &'static str,
+    status: Option<StatusCode>,
+}
+impl Predicate {
+    fn new(check: Check, err_msg: &'static str) -> Self {
+        Self { check, err_msg, status: None }
+    }
+    pub fn path_glob(pattern: &str) -> Self {
+        Self::new(Check::Path(Arc::new(glob(pattern))), "path not allowed")
+    }
+    pub fn path_regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self::new(Check::Path(Arc::new(Regex::new(pattern)?)), "path not allowed"))
+    }
+    /// The host, ignoring case and port.
+    pub fn host(host: &str) -> Self {
+        Self::new(Check::Host(host.to_ascii_lowercase()), "host not allowed")
+    }
+    /// Any host below `domain`, but not `domain` itself.
+    pub fn subdomain_of(domain: &str) -> Self {
+        Self::new(Check::SubdomainOf(domain.to_ascii_lowercase()), "host not allowed")
+    }
+    pub fn header_present(name: &str) -> Self {
+        Self::new(Check::HeaderPresent(name.to_ascii_lowercase()), "missing header")
+    }
+    pub fn header_equals(name: &str, value: &str) -> Self {
+        Self::new(Check::HeaderEquals(name.to_ascii_lowercase(), value.to_string()), "invalid header")
+    }
+    pub fn header_regex(name: &str, pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self::new(Check::HeaderRegex(name.to_ascii_lowercase(), Arc::new(Regex::new(pattern)?)), "invalid header"))
+    }
+    pub fn query_equals(name: &str, value: &str) -> Self {
+        Self::new(Check::QueryEquals(name.to_string(), value.to_string()), "invalid query")
+    }
+    pub fn err_msg(mut self, err_msg: &'static str) -> Self {
+        self.err_msg = err_msg;
+        self
+    }
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+}
+
+/// Adds `Method::any_of`.
+pub trait MethodPredicate {
+    fn any_of<I: IntoIterator<Item = Method>>(methods: I) -> Predicate;
+}
+impl MethodPredicate for Method {
+    fn any_of<I: IntoIterator<Item = Method>>(methods: I) -> Predicate {
+        Predicate::new(Check::Methods(methods.into_iter().collect()), "method not allowed")
+    }
+}
+
+impl Service<Parts> for Predicate {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let passed = self.check.test(&parts);
+        if !passed {
+            if let Some(status) = self.status {
+                GuardDenial::status(status).merge_into(&mut parts);
+            }
+        }
+        let err_msg = if passed { None } else { Some(String::from(self.err_msg)) };
+        Box::pin(async move { Ok(GuardServiceResponse((passed, err_msg), parts)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use crate::tests::ArbitraryData;
+    use super::*;
+
+    fn test(predicate: &Predicate, req: http::request::Builder) -> bool {
+        let (parts, _) = req.body(()).unwrap().into_parts();
+        predicate.check.test(&parts)
+    }
+
+    #[test]
+    fn test_path_and_host() {
+        let glob = Predicate::path_glob("/api/*/items/**");
+        assert!(test(&glob, Request::get("/api/v1/items/7/history")));
+        assert!(!test(&glob, Request::get("/api/v1/beta/items/7")));
+        let regex = Predicate::path_regex(r"^/users/\d+$").unwrap();
+        assert!(test(&regex, Request::get("/users/42")));
+        assert!(!test(&regex, Request::get("/users/me")));
+        let host = Predicate::host("Admin.Example.com");
+        assert!(test(&host, Request::get("/").header("host", "admin.example.com:8080")));
+        assert!(test(&host, Request::get("https://admin.example.com/")));
+        let sub = Predicate::subdomain_of("example.com");
+        assert!(test(&sub, Request::get("/").header("host", "tenant.example.com")));
+        assert!(!test(&sub, Request::get("/").header("host", "example.com")));
+        assert!(!test(&sub, Request::get("/").header("host", "badexample.com")));
+    }
+
+    #[test]
+    fn test_headers_and_query() {
+        assert!(test(&Predicate::header_present("X-Debug"), Request::get("/").header("x-debug", "")));
+        let equals = Predicate::header_equals("x-env", "staging");
+        assert!(test(&equals, Request::get("/").header("x-env", "prod").header("x-env", "staging")));
+        let regex = Predicate::header_regex("user-agent", "^curl/").unwrap();
+        assert!(!test(&regex, Request::get("/").header("user-agent", "Mozilla/5.0")));
+        let query = Predicate::query_equals("format", "csv file");
+        assert!(test(&query, Request::get("/?page=2&format=csv+file")));
+        assert!(!test(&query, Request::get("/?format=json")));
+    }
+
+    #[tokio::test]
+    async fn test_compose_with_credentials() {
+        let admin = GuardService::new(ArbitraryData::new("x-role"), ArbitraryData::new("admin"), "admins only");
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }).delete(|| async { StatusCode::OK }))
+            .layer(Method::any_of([Method::GET, Method::HEAD]).or(admin).into_layer());
+        let req = |method: Method, role: &str| Request::builder()
+            .method(method)
+            .uri("/")
+            .header("x-role", role)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(req(Method::GET, "guest")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(req(Method::DELETE, "guest")).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(app.oneshot(req(Method::DELETE, "admin")).await.unwrap().status(), StatusCode::OK);
+
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(Predicate::host("internal.example.com").status(StatusCode::NOT_FOUND).into_layer());
+        let req = Request::get("/").header("host", "example.com").body(Body::empty()).unwrap();
+        assert_eq!(app.oneshot(req).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+}
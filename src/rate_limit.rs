@@ -0,0 +1,299 @@
+//! Rate limiting as a guard, so quotas compose with credentials:
+//! `authenticated.or(RateLimit::by_ip(Quota::per_minute(60), ip_config))`.
+//!
+//! The in-memory [`MemoryStore`] implements GCRA, a token bucket without a refill timer;
+//! a shared store such as Redis plugs in through [`RateLimitStore`].
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, UNIX_EPOCH};
+use futures_core::future::BoxFuture;
+use http::{HeaderName, HeaderValue, StatusCode};
+use http::header::RETRY_AFTER;
+use http::request::Parts;
+use tower_service::Service;
+use crate::{GuardDenial, GuardServiceResponse};
+use crate::ip::IpConfig;
+use crate::schedule::{Clock, SystemClock};
+
+/// `limit` requests per `period`, of which up to `burst` at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    limit: u32,
+    period: Duration,
+    burst: u32,
+}
+impl Quota {
+    /// Allows the whole `limit` as a burst; lower it with [`Quota::burst`].
+    pub fn new(limit: u32, period: Duration) -> Self {
+        let limit = limit.max(1);
+        Self { limit, period, burst: limit }
+    }
+    pub fn per_second(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(1))
+    }
+    pub fn per_minute(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(60))
+    }
+    pub fn per_hour(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(3600))
+    }
+    pub fn burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+    /// How many requests may arrive at once, at least 1.
+    pub fn burst_size(&self) -> u32 {
+        self.burst
+    }
+    /// The time one request uses up.
+    pub fn interval(&self) -> Duration {
+        self.period / self.limit
+    }
+}
+
+/// The outcome of counting one request against a quota.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the quota is whole again.
+    pub reset: Duration,
+    /// Until the next request would be allowed, when this one was not.
+    pub retry_after: Option<Duration>,
+}
+
+/// Where the counts live. A store error rejects the request with `503`.
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Counts a request for `key`, unless it is over `quota`.
+    async fn hit(&self, key: &str, quota: &Quota) -> Result<RateLimitDecision, String>;
+}
+
+/// GCRA over a map of theoretical arrival times, one per key, in this process only.
+pub struct MemoryStore {
+    clock: Arc<dyn Clock>,
+    arrivals: Mutex<Arrivals>,
+}
+#[derive(Default)]
+struct Arrivals {
+    tat: HashMap<String, Duration>,
+    sweep_at: usize,
+}
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self { clock: Arc::new(SystemClock), arrivals: Mutex::default() }
+    }
+}
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+#[async_trait::async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn hit(&self, key: &str, quota: &Quota) -> Result<RateLimitDecision, String> {
+        let now = self.clock.now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let interval = quota.interval();
+        let tolerance = interval * quota.burst;
+        let mut arrivals = self.arrivals.lock().map_err(|_| String::from("rate limit store poisoned"))?;
+        if arrivals.tat.len() >= arrivals.sweep_at {
+            // Keys whose arrival time has passed are back to a full quota, forgetting them changes nothing.
+            arrivals.tat.retain(|_, tat| *tat > now);
+            arrivals.sweep_at = (arrivals.tat.len() * 2).max(1024);
+        }
+        let tat = arrivals.tat.get(key).copied().unwrap_or(now).max(now);
+        let next = tat + interval;
+        let allow_at = next.saturating_sub(tolerance);
+        let remaining = |tat: Duration| {
+            let used = tat.saturating_sub(now).as_nanos().div_ceil(interval.as_nanos().max(1));
+            quota.burst.saturating_sub(used as u32)
+        };
+        if now < allow_at {
+            return Ok(RateLimitDecision {
+                allowed: false,
+                limit: quota.limit,
+                remaining: remaining(tat),
+                reset: tat - now,
+                retry_after: Some(allow_at - now),
+            });
+        }
+        arrivals.tat.insert(key.to_string(), next);
+        Ok(RateLimitDecision { allowed: true, limit: quota.limit, remaining: remaining(next), reset: next - now, retry_after: None })
+    }
+}
+
+type KeySource = Arc<dyn Fn(&Parts) -> Option<String> + Send + Sync>;
+
+/// A guard service counting requests per key, e.g. the client address with
+/// [`RateLimit::by_ip`], an API key or a user id.
+///
+/// Over the quota it fails with `429`, `Retry-After` and the `RateLimit-Limit`,
+/// `RateLimit-Remaining` and `RateLimit-Reset` headers. A request without a key fails
+/// with the status left to the rest of the tree. On success the [`RateLimitDecision`]
+/// is inserted into the request extensions.
+#[derive(Clone)]
+pub struct RateLimit {
+    quota: Quota,
+    key: KeySource,
+    store: Arc<dyn RateLimitStore>,
+    err_msg: &'static str,
+}
+impl RateLimit {
+    /// Counts in a [`MemoryStore`] of its own.
+    pub fn new(quota: Quota, key: impl Fn(&Parts) -> Option<String> + Send + Sync + 'static) -> Self {
+        Self { quota, key: Arc::new(key), store: Arc::new(MemoryStore::new()), err_msg: "too many requests" }
+    }
+    /// Counts per client address, resolved through the trusted proxies of `config`.
+    pub fn by_ip(quota: Quota, config: IpConfig) -> Self {
+        Self::new(quota, move |parts| config.client(parts).map(|ip| ip.to_string()))
+    }
+    pub fn store(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.store = store;
+        self
+    }
+    pub fn err_msg(mut self, err_msg: &'static str) -> Self {
+        self.err_msg = err_msg;
+        self
+    }
+}
+
+fn header_secs(duration: Duration) -> HeaderValue {
+    HeaderValue::from(duration.as_secs() + u64::from(duration.subsec_nanos() > 0))
+}
+
+impl Service<Parts> for RateLimit {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut parts: Parts) -> Self::Future {
+        let key = (self.key)(&parts);
+        let quota = self.quota;
+        let store = self.store.clone();
+        let err_msg = self.err_msg;
+        Box::pin(async move {
+            let Some(key) = key else {
+                return Ok(GuardServiceResponse((false, Some(String::from(err_msg))), parts));
+            };
+            let decision = store.hit(&key, &quota).await.map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err))?;
+            if decision.allowed {
+                parts.extensions.insert(decision);
+                return Ok(GuardServiceResponse((true, None), parts));
+            }
+            let mut denial = GuardDenial::status(StatusCode::TOO_MANY_REQUESTS)
+                .with_header(HeaderName::from_static("ratelimit-limit"), HeaderValue::from(decision.limit))
+                .with_header(HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(decision.remaining))
+                .with_header(HeaderName::from_static("ratelimit-reset"), header_secs(decision.reset));
+            if let Some(retry_after) = decision.retry_after {
+                denial = denial.with_header(RETRY_AFTER, header_secs(retry_after));
+            }
+            denial.merge_into(&mut parts);
+            Ok(GuardServiceResponse((false, Some(String::from(err_msg))), parts))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use crate::schedule::ManualClock;
+    use crate::tests::ArbitraryData;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gcra() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let store = MemoryStore::new().clock(clock.clone());
+        let quota = Quota::per_minute(60).burst(3);
+        for remaining in [2, 1, 0] {
+            let decision = store.hit("a", &quota).await.unwrap();
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = store.hit("a", &quota).await.unwrap();
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(denied.reset, Duration::from_secs(3));
+        assert!(store.hit("b", &quota).await.unwrap().allowed);
+        clock.advance(Duration::from_secs(1));
+        assert!(store.hit("a", &quota).await.unwrap().allowed);
+        assert!(!store.hit("a", &quota).await.unwrap().allowed);
+    }
+
+    #[test]
+    fn test_quota_clamped() {
+        let quota = Quota::per_second(0).burst(0);
+        assert_eq!((quota.limit(), quota.burst_size()), (1, 1));
+        assert_eq!(quota.interval(), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_by_ip() {
+        let config = IpConfig::new().trust_proxies(["10.0.0.0/8"]).unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(RateLimit::by_ip(Quota::per_hour(1), config).into_layer());
+        let req = |peer: &str, forwarded: &str| {
+            let mut req = Request::get("/").header("x-forwarded-for", forwarded).body(Body::empty()).unwrap();
+            req.extensions_mut().insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+            req
+        };
+        assert_eq!(app.clone().oneshot(req("10.0.0.1:80", "192.0.2.1")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(req("10.0.0.2:80", "192.0.2.1")).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(app.clone().oneshot(req("10.0.0.1:80", "192.0.2.2")).await.unwrap().status(), StatusCode::OK);
+        // A peer that isn't a proxy can't pick its key through the header.
+        assert_eq!(app.clone().oneshot(req("192.0.2.9:80", "192.0.2.3")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.oneshot(req("192.0.2.9:80", "192.0.2.4")).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_or_limited() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let store = Arc::new(MemoryStore::new().clock(clock.clone()));
+        let anonymous = RateLimit::new(Quota::per_minute(2), |parts| {
+            parts.headers.get("x-client").and_then(|client| client.to_str().ok()).map(String::from)
+        })
+        .store(store);
+        let authenticated = GuardService::new(ArbitraryData::new("x-user"), ArbitraryData::new("alice"), "unauthenticated");
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(authenticated.or(anonymous).into_layer());
+        let req = |user: &str| Request::get("/").header("x-user", user).header("x-client", "192.0.2.1").body(Body::empty()).unwrap();
+        for _ in 0..2 {
+            assert_eq!(app.clone().oneshot(req("")).await.unwrap().status(), StatusCode::OK);
+        }
+        let resp = app.clone().oneshot(req("")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "30");
+        assert_eq!(resp.headers()["ratelimit-limit"], "2");
+        assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+        assert_eq!(resp.headers()["ratelimit-reset"], "60");
+        assert_eq!(app.clone().oneshot(req("alice")).await.unwrap().status(), StatusCode::OK);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(app.oneshot(req("")).await.unwrap().status(), StatusCode::OK);
+    }
+}
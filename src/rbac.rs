@@ -0,0 +1,247 @@
+//! Role based access control with role inheritance.
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use axum_core::extract::FromRequestParts;
+use http::StatusCode;
+use http::request::Parts;
+use crate::{Guard, GuardDenial};
+
+/// Returned by [`RoleHierarchyBuilder::build`] when roles imply each other in a loop.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleCycle(pub Vec<String>);
+impl fmt::Display for RoleCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "role inheritance cycle: {}", self.0.join(" -> "))
+    }
+}
+impl std::error::Error for RoleCycle {}
+
+#[derive(Clone, Debug, Default)]
+pub struct RoleHierarchyBuilder {
+    implies: HashMap<String, Vec<String>>,
+}
+impl RoleHierarchyBuilder {
+    /// Anyone with `role` also has `implied`, and everything `implied` implies.
+    pub fn inherit(mut self, role: impl Into<String>, implied: impl Into<String>) -> Self {
+        self.implies.entry(role.into()).or_default().push(implied.into());
+        self
+    }
+    pub fn build(self) -> Result<RoleHierarchy, RoleCycle> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark {
+            Visiting,
+            Done,
+        }
+        fn visit<'a>(
+            role: &'a str,
+            implies: &'a HashMap<String, Vec<String>>,
+            marks: &mut HashMap<&'a str, Mark>,
+            path: &mut Vec<&'a str>,
+        ) -> Result<(), RoleCycle> {
+            match marks.get(role) {
+                Some(Mark::Done) => return Ok(()),
+                Some(Mark::Visiting) => {
+                    let start = path.iter().position(|r| *r == role).unwrap_or(0);
+                    let mut cycle: Vec<String> = path[start..].iter().map(|r| r.to_string()).collect();
+                    cycle.push(role.to_string());
+                    return Err(RoleCycle(cycle));
+                }
+                None => {}
+            }
+            marks.insert(role, Mark::Visiting);
+            path.push(role);
+            for implied in implies.get(role).into_iter().flatten() {
+                visit(implied, implies, marks, path)?;
+            }
+            path.pop();
+            marks.insert(role, Mark::Done);
+            Ok(())
+        }
+        let mut marks = HashMap::new();
+        let mut roots: Vec<&String> = self.implies.keys().collect();
+        roots.sort();
+        for role in roots {
+            visit(role, &self.implies, &mut marks, &mut Vec::new())?;
+        }
+        Ok(RoleHierarchy { implies: self.implies })
+    }
+}
+
+/// Which roles imply which. Guaranteed acyclic.
+#[derive(Clone, Debug, Default)]
+pub struct RoleHierarchy {
+    implies: HashMap<String, Vec<String>>,
+}
+impl RoleHierarchy {
+    pub fn builder() -> RoleHierarchyBuilder {
+        RoleHierarchyBuilder::default()
+    }
+    /// `roles` plus every role they imply.
+    pub fn effective<I, T>(&self, roles: I) -> BTreeSet<String>
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<String>, {
+        let mut effective = BTreeSet::new();
+        let mut pending: Vec<String> = roles.into_iter().map(Into::into).collect();
+        while let Some(role) = pending.pop() {
+            if let Some(implied) = self.implies.get(&role) {
+                pending.extend(implied.iter().filter(|r| !effective.contains(*r)).cloned());
+            }
+            effective.insert(role);
+        }
+        effective
+    }
+}
+
+type RoleSource = Arc<dyn Fn(&Parts) -> Vec<String> + Send + Sync>;
+
+/// The state passed to `GuardService::new`: the hierarchy and how to find the roles
+/// assigned to the caller, usually from a principal an earlier guard put in the extensions.
+#[derive(Clone)]
+pub struct RbacConfig {
+    hierarchy: Arc<RoleHierarchy>,
+    source: RoleSource,
+}
+impl RbacConfig {
+    pub fn new(hierarchy: RoleHierarchy, source: impl Fn(&Parts) -> Vec<String> + Send + Sync + 'static) -> Self {
+        Self { hierarchy: Arc::new(hierarchy), source: Arc::new(source) }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    All,
+    Any,
+}
+
+/// Extracted: the caller's effective roles, inheritance applied.
+/// Expected: the roles the route requires.
+///
+/// Failing with roles present is a `403`. On success the effective roles are inserted
+/// into the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Roles {
+    roles: BTreeSet<String>,
+    mode: Mode,
+}
+impl Roles {
+    /// Every one of `roles` is required.
+    pub fn all<I, T>(roles: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<String>, {
+        Self { roles: roles.into_iter().map(Into::into).collect(), mode: Mode::All }
+    }
+    /// At least one of `roles` is required.
+    pub fn any_of<I, T>(roles: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<String>, {
+        Self { roles: roles.into_iter().map(Into::into).collect(), mode: Mode::Any }
+    }
+    pub fn contains(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.roles.iter().map(String::as_str)
+    }
+}
+impl Guard for Roles {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match expected.mode {
+            Mode::All => expected.roles.is_subset(&self.roles),
+            Mode::Any => expected.roles.iter().any(|role| self.roles.contains(role)),
+        }
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        if self.roles.is_empty() {
+            GuardDenial::default()
+        } else {
+            GuardDenial::status(StatusCode::FORBIDDEN)
+        }
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        parts.extensions.insert(self);
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<RbacConfig> for Roles {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &RbacConfig) -> Result<Self, Self::Rejection> {
+        Ok(Self { roles: state.hierarchy.effective((state.source)(parts)), mode: Mode::All })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    async fn ok() -> StatusCode {
+        StatusCode::OK
+    }
+    fn hierarchy() -> RoleHierarchy {
+        RoleHierarchy::builder()
+            .inherit("admin", "editor")
+            .inherit("editor", "viewer")
+            .inherit("billing", "viewer")
+            .build()
+            .unwrap()
+    }
+    fn app(expected: Roles) -> Router {
+        let config = RbacConfig::new(hierarchy(), |parts| {
+            parts.headers.get_all("x-role").iter()
+                .filter_map(|role| role.to_str().ok())
+                .map(String::from)
+                .collect()
+        });
+        Router::new()
+            .route("/", get(ok))
+            .layer(GuardService::new(config, expected, "missing role").into_layer())
+    }
+    fn request(roles: &[&'static str]) -> Request<Body> {
+        roles.iter()
+            .fold(Request::get("/"), |builder, role| builder.header("x-role", *role))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_effective_roles() {
+        let effective = hierarchy().effective(["admin"]);
+        assert_eq!(effective.into_iter().collect::<Vec<_>>(), ["admin", "editor", "viewer"]);
+    }
+
+    #[test]
+    fn test_cycle_detection() {
+        let cycle = RoleHierarchy::builder()
+            .inherit("a", "b")
+            .inherit("b", "c")
+            .inherit("c", "a")
+            .build()
+            .unwrap_err();
+        assert_eq!(cycle.0.first(), cycle.0.last());
+        assert_eq!(cycle.0.len(), 4);
+        assert!(RoleHierarchy::builder().inherit("a", "a").build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inherited_role() {
+        let resp = app(Roles::all(["viewer"])).oneshot(request(&["admin"])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app(Roles::all(["editor", "viewer"])).oneshot(request(&["billing"])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = app(Roles::any_of(["editor", "billing"])).oneshot(request(&["billing"])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app(Roles::all(["viewer"])).oneshot(request(&[])).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
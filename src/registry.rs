@@ -0,0 +1,102 @@
+//! Named guards, so guard trees can be assembled from expressions and config at startup.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use http::StatusCode;
+use http::request::Parts;
+use tower_service::Service;
+use crate::{BoxGuardService, GuardLayer, GuardServiceExt, GuardServiceResponse};
+use crate::expr::{ExprError, GuardExpr};
+
+type Constructor = Arc<dyn Fn() -> BoxGuardService + Send + Sync>;
+
+/// Maps names to constructors of guard services, each with its own state and expected value.
+#[derive(Clone, Default)]
+pub struct GuardRegistry {
+    guards: BTreeMap<String, Constructor>,
+}
+impl GuardRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Registers `constructor` under `name`, replacing any guard already registered with it.
+    pub fn register<F, S>(mut self, name: impl Into<String>, constructor: F) -> Self
+        where
+            F: Fn() -> S + Send + Sync + 'static,
+            S: Service<Parts, Response = GuardServiceResponse, Error = (StatusCode, String)> + Send + Sync + Clone + 'static,
+            <S as Service<Parts>>::Future: Send + 'static, {
+        self.guards.insert(name.into(), Arc::new(move || constructor().boxed_guard()));
+        self
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.guards.contains_key(name)
+    }
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.guards.keys().map(String::as_str)
+    }
+    /// A new instance of the guard registered under `name`.
+    pub fn get(&self, name: &str) -> Option<BoxGuardService> {
+        self.guards.get(name).map(|constructor| constructor())
+    }
+    /// Every name in `expr` that isn't registered, so config can be rejected with all of them at once.
+    pub fn unknown(&self, expr: &GuardExpr) -> Vec<ExprError> {
+        expr.names().into_iter()
+            .filter(|(name, _)| !self.contains(name))
+            .map(|(name, span)| ExprError { message: format!("unknown guard '{}'", name), span })
+            .collect()
+    }
+    pub fn build_expr(&self, expr: &GuardExpr) -> Result<BoxGuardService, ExprError> {
+        expr.build(&mut |name| self.get(name))
+    }
+    /// Parses `source` and builds its guard tree.
+    pub fn build(&self, source: &str) -> Result<BoxGuardService, ExprError> {
+        self.build_expr(&GuardExpr::parse(source)?)
+    }
+    pub fn layer<ReqBody>(&self, source: &str) -> Result<GuardLayer<BoxGuardService, ReqBody>, ExprError> {
+        Ok(self.build(source)?.into_layer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::GuardService;
+    use crate::tests::ArbitraryData;
+    use super::*;
+
+    fn registry() -> GuardRegistry {
+        GuardRegistry::new()
+            .register("api_key", || GuardService::new(ArbitraryData::new("x-api-key"), ArbitraryData::new("secret"), "invalid key"))
+            .register("internal_ip", || GuardService::new(ArbitraryData::new("x-zone"), ArbitraryData::new("internal"), "not internal"))
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(registry().layer("api_key & internal_ip").unwrap());
+        let req = |zone: &str| Request::get("/")
+            .header("x-api-key", "secret")
+            .header("x-zone", zone)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.clone().oneshot(req("internal")).await.unwrap().status(), StatusCode::OK);
+        let resp = app.oneshot(req("public")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(&hyper::body::to_bytes(resp.into_body()).await.unwrap()[..], b"not internal");
+    }
+
+    #[test]
+    fn test_unknown_names() {
+        let registry = registry();
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["api_key", "internal_ip"]);
+        let expr = GuardExpr::parse("api_key & (admin | vpn)").unwrap();
+        let unknown: Vec<_> = registry.unknown(&expr).into_iter().map(|err| err.span).collect();
+        assert_eq!(unknown, [11..16, 19..22]);
+        assert_eq!(registry.build_expr(&expr).err().unwrap().span, 11..16);
+        assert!(registry.build("api_key |").is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Route policies read from a file and swapped in when it changes. Requires the `config` feature.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use futures_core::future::BoxFuture;
+use http::StatusCode;
+use http::request::Parts;
+use tokio::sync::broadcast;
+use tower_service::Service;
+use crate::GuardServiceResponse;
+use crate::config::{ConfigError, GuardPolicies, PolicyConfig, PolicyFormat};
+use crate::registry::GuardRegistry;
+
+/// The outcome of a reload that found the file changed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReloadEvent {
+    Reloaded,
+    /// The new policies were rejected and the previous ones stay active.
+    Failed(String),
+}
+
+struct Seen {
+    /// The contents the active policies were built from.
+    active: String,
+    /// A hash of the contents or read error that failed last.
+    failed: Option<u64>,
+}
+
+fn fingerprint(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Loads a [`PolicyConfig`] file and keeps the built policies current.
+///
+/// Every reload re-reads the file and, if its contents changed, parses it and builds it
+/// against the registry. Only a policy that builds completely replaces the active one,
+/// so a typo in the file never leaves routes unguarded. A failure is reported once;
+/// the same broken contents, or the same read error, are then skipped until they change.
+pub struct PolicyFile {
+    path: PathBuf,
+    format: PolicyFormat,
+    registry: GuardRegistry,
+    active: Arc<RwLock<GuardPolicies>>,
+    seen: Mutex<Seen>,
+    events: broadcast::Sender<ReloadEvent>,
+}
+impl PolicyFile {
+    /// Loads `path`, guessing the format from its extension. Fails if the first load does.
+    pub fn load(path: impl Into<PathBuf>, registry: GuardRegistry) -> Result<Arc<Self>, ConfigError> {
+        let path = path.into();
+        let format = PolicyFormat::from_path(&path).ok_or_else(|| ConfigError::UnknownFormat(path.clone()))?;
+        Self::load_as(path, format, registry)
+    }
+    pub fn load_as(path: impl Into<PathBuf>, format: PolicyFormat, registry: GuardRegistry) -> Result<Arc<Self>, ConfigError> {
+        let path = path.into();
+        let contents = std::fs::read_to_string(&path)?;
+        let policies = PolicyConfig::parse(format, &contents)?.build(&registry)?;
+        Ok(Arc::new(Self {
+            path,
+            format,
+            registry,
+            active: Arc::new(RwLock::new(policies)),
+            seen: Mutex::new(Seen { active: contents, failed: None }),
+            events: broadcast::channel(16).0,
+        }))
+    }
+    /// A guard service that always runs the currently active policies.
+    pub fn policies(&self) -> ReloadingPolicies {
+        ReloadingPolicies { active: self.active.clone() }
+    }
+    pub fn subscribe(&self) -> broadcast::Receiver<ReloadEvent> {
+        self.events.subscribe()
+    }
+    /// Reloads now. `Ok(false)` if nothing changed since the last reload, including
+    /// contents or a read error that already failed.
+    pub async fn reload(&self) -> Result<bool, ConfigError> {
+        let result = self.try_reload().await;
+        match &result {
+            Ok(false) => {}
+            Ok(true) => {
+                let _ = self.events.send(ReloadEvent::Reloaded);
+            }
+            Err(err) => {
+                let _ = self.events.send(ReloadEvent::Failed(err.to_string()));
+            }
+        }
+        result
+    }
+    async fn try_reload(&self) -> Result<bool, ConfigError> {
+        let read = tokio::fs::read_to_string(&self.path).await;
+        let mut seen = self.seen.lock().unwrap();
+        let fingerprint = match &read {
+            Ok(contents) if *contents == seen.active => {
+                seen.failed = None;
+                return Ok(false);
+            }
+            Ok(contents) => fingerprint(contents),
+            Err(err) => fingerprint(&err.to_string()),
+        };
+        if seen.failed == Some(fingerprint) {
+            return Ok(false);
+        }
+        let built = read.map_err(ConfigError::from)
+            .and_then(|contents| Ok((PolicyConfig::parse(self.format, &contents)?.build(&self.registry)?, contents)));
+        match built {
+            Ok((policies, contents)) => {
+                *self.active.write().unwrap() = policies;
+                *seen = Seen { active: contents, failed: None };
+                Ok(true)
+            }
+            Err(err) => {
+                seen.failed = Some(fingerprint);
+                Err(err)
+            }
+        }
+    }
+    /// Polls the file every `interval` on the current tokio runtime until the task is aborted.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let file = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let _ = file.reload().await;
+            }
+        })
+    }
+}
+
+/// The active policies of a [`PolicyFile`], used like any other guard service.
+#[derive(Clone)]
+pub struct ReloadingPolicies {
+    active: Arc<RwLock<GuardPolicies>>,
+}
+impl Service<Parts> for ReloadingPolicies {
+    type Response = GuardServiceResponse;
+    type Error = (StatusCode, String);
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, parts: Parts) -> Self::Future {
+        // Requests already running keep the policies they started with.
+        let mut policies = self.active.read().unwrap().clone();
+        policies.call(parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use crate::tests::ArbitraryData;
+    use super::*;
+
+    fn registry() -> GuardRegistry {
+        ["admin", "editor"].into_iter().fold(GuardRegistry::new(), |registry, name| {
+            registry.register(name, move || GuardService::new(ArbitraryData::new(name), ArbitraryData::new("yes"), "missing role"))
+        })
+    }
+    fn write_policy(path: &PathBuf, policy: &str) {
+        std::fs::write(path, format!("[[routes]]\npath = \"/*rest\"\npolicy = \"{}\"\n", policy)).unwrap();
+    }
+    async fn status(app: &Router, role: &str) -> StatusCode {
+        let req = ["admin", "editor"].iter()
+            .fold(Request::get("/"), |req, name| req.header(*name, if *name == role { "yes" } else { "no" }))
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = std::env::temp_dir().join(format!("axum_guard_logic_reload_{}.toml", std::process::id()));
+        write_policy(&path, "admin");
+        let file = PolicyFile::load(&path, registry()).unwrap();
+        let mut events = file.subscribe();
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(file.policies().into_layer());
+        assert_eq!(status(&app, "editor").await, StatusCode::UNAUTHORIZED);
+        assert!(!file.reload().await.unwrap());
+
+        write_policy(&path, "admin | editor");
+        assert!(file.reload().await.unwrap());
+        assert_eq!(events.recv().await.unwrap(), ReloadEvent::Reloaded);
+        assert_eq!(status(&app, "editor").await, StatusCode::OK);
+
+        write_policy(&path, "admin | ghost");
+        assert!(file.reload().await.is_err());
+        assert!(matches!(events.recv().await.unwrap(), ReloadEvent::Failed(message) if message.contains("ghost")));
+        assert_eq!(status(&app, "editor").await, StatusCode::OK);
+        // The same failure is reported once.
+        assert!(!file.reload().await.unwrap());
+        assert!(events.try_recv().is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.reload().await.is_err());
+        assert!(matches!(events.recv().await.unwrap(), ReloadEvent::Failed(_)));
+        assert!(!file.reload().await.unwrap());
+        assert!(events.try_recv().is_err());
+        assert_eq!(status(&app, "editor").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let path = std::env::temp_dir().join(format!("axum_guard_logic_watch_{}.toml", std::process::id()));
+        write_policy(&path, "admin");
+        let file = PolicyFile::load(&path, registry()).unwrap();
+        let mut events = file.subscribe();
+        let watcher = file.watch(Duration::from_millis(10));
+        write_policy(&path, "editor");
+        assert_eq!(events.recv().await.unwrap(), ReloadEvent::Reloaded);
+        watcher.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
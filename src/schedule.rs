@@ -0,0 +1,359 @@
+//! Guards that only pass during certain times, such as maintenance windows.
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum_core::extract::FromRequestParts;
+use http::StatusCode;
+use http::request::Parts;
+use crate::{Guard, GuardDenial};
+
+/// Where a [`TimeWindow`] gets the current time from.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+impl ManualClock {
+    pub fn new(now: SystemTime) -> Self {
+        let clock = Self::default();
+        clock.set(now);
+        clock
+    }
+    pub fn set(&self, now: SystemTime) {
+        self.0.store(now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(), Ordering::SeqCst);
+    }
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_secs(), Ordering::SeqCst);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// The state passed to `GuardService::new`: the clock and the timezone windows are written in.
+#[derive(Clone)]
+pub struct ScheduleConfig {
+    clock: Arc<dyn Clock>,
+    offset_minutes: i32,
+}
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self { clock: Arc::new(SystemClock), offset_minutes: 0 }
+    }
+}
+impl ScheduleConfig {
+    /// The system clock, windows in UTC.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    /// Windows are in local time `offset_minutes` ahead of UTC, e.g. `-300` for UTC-5.
+    pub fn utc_offset(mut self, offset_minutes: i32) -> Self {
+        self.offset_minutes = offset_minutes;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+/// A broken down local time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct LocalTime {
+    minute: u32,
+    hour: u32,
+    day: u32,
+    month: u32,
+    /// Days since Sunday.
+    weekday: u32,
+}
+impl LocalTime {
+    fn new(now: SystemTime, offset_minutes: i32) -> Self {
+        let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64 + offset_minutes as i64 * 60;
+        let days = secs.div_euclid(86_400);
+        let of_day = secs.rem_euclid(86_400) as u32;
+        // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`.
+        let doe = (days + 719_468).rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        Self {
+            minute: of_day / 60 % 60,
+            hour: of_day / 3600,
+            day: (doy - (153 * mp + 2) / 5 + 1) as u32,
+            month: if mp < 10 { mp + 3 } else { mp - 9 } as u32,
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+    fn minute_of_week(&self) -> u32 {
+        self.weekday * 1440 + self.hour * 60 + self.minute
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronError(pub String);
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cron expression: {}", self.0)
+    }
+}
+impl std::error::Error for CronError {}
+
+/// An `(hour, minute)` outside `00:00`-`23:59`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeOfDayError(pub u32, pub u32);
+impl fmt::Display for TimeOfDayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid time of day {}:{:02}", self.0, self.1)
+    }
+}
+impl std::error::Error for TimeOfDayError {}
+
+/// `minute hour day-of-month month day-of-week`, each field a bit set of the values it matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+impl Cron {
+    fn parse(source: &str) -> Result<Self, CronError> {
+        const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+        const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CronError(format!("expected 5 fields, found {}", fields.len())));
+        };
+        let mut weekday_bits = field(weekdays, 0, 7, &DAYS, 0)?;
+        // 7 is Sunday too.
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits |= 1;
+        }
+        Ok(Self {
+            minutes: field(minutes, 0, 59, &[], 0)?,
+            hours: field(hours, 0, 23, &[], 0)?,
+            days: field(days, 1, 31, &[], 0)?,
+            months: field(months, 1, 12, &MONTHS, 1)?,
+            weekdays: weekday_bits,
+            // Like cron, a field starting with `*`, e.g. `*/2`, doesn't count as restricted.
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+    fn matches(&self, time: &LocalTime) -> bool {
+        let bit = |set: u64, value: u32| set & (1 << value) != 0;
+        let day = bit(self.days, time.day);
+        let weekday = bit(self.weekdays, time.weekday);
+        // As in cron, when both day fields are restricted either may match.
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+        bit(self.minutes, time.minute) && bit(self.hours, time.hour) && bit(self.months, time.month) && day_matches
+    }
+}
+
+fn field(source: &str, min: u32, max: u32, names: &[&str], first_name: u32) -> Result<u64, CronError> {
+    let value = |text: &str| -> Result<u32, CronError> {
+        let lower = text.to_ascii_lowercase();
+        let value = names.iter().position(|name| *name == lower)
+            .map(|i| i as u32 + first_name)
+            .or_else(|| text.parse().ok())
+            .ok_or_else(|| CronError(format!("invalid value {}", text)))?;
+        if value < min || value > max {
+            return Err(CronError(format!("{} is outside {}-{}", value, min, max)));
+        }
+        Ok(value)
+    };
+    let mut bits = 0;
+    for part in source.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)
+                .ok_or_else(|| CronError(format!("invalid step {}", step)))?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return Err(CronError(format!("empty range {}", range)));
+        }
+        bits |= (start..=end).step_by(step as usize).fold(0, |bits, value| bits | (1 << value));
+    }
+    Ok(bits)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Window {
+    /// Minutes of the day, `end` before `start` wrapping into the next day.
+    Weekly { days: Vec<Weekday>, start: u32, end: u32 },
+    Cron(Cron),
+}
+impl Window {
+    fn contains(&self, time: &LocalTime) -> bool {
+        match self {
+            Window::Cron(cron) => cron.matches(time),
+            Window::Weekly { days, start, end } => {
+                let now = time.minute_of_week();
+                let length = if end > start { end - start } else { end + 1440 - start };
+                days.iter().any(|day| {
+                    let opens = *day as u32 * 1440 + start;
+                    (now + 7 * 1440 - opens) % (7 * 1440) < length
+                })
+            }
+        }
+    }
+}
+
+/// Extracted: the current local time.
+/// Expected: the windows the route is open in, passing when the time falls in any of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimeWindow {
+    now: Option<LocalTime>,
+    windows: Vec<Window>,
+}
+impl TimeWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Open from `start` until `end` (`(hour, minute)`, end exclusive) on each of `days`.
+    /// An `end` before `start` runs past midnight into the next day.
+    pub fn weekly(mut self, days: impl IntoIterator<Item = Weekday>, start: (u32, u32), end: (u32, u32)) -> Result<Self, TimeOfDayError> {
+        let minute_of_day = |(hour, minute): (u32, u32)| {
+            if hour < 24 && minute < 60 { Ok(hour * 60 + minute) } else { Err(TimeOfDayError(hour, minute)) }
+        };
+        self.windows.push(Window::Weekly {
+            days: days.into_iter().collect(),
+            start: minute_of_day(start)?,
+            end: minute_of_day(end)?,
+        });
+        Ok(self)
+    }
+    /// Open during every minute a `minute hour day-of-month month day-of-week` cron expression
+    /// matches, e.g. `* 2-4 * * sat,sun` for 02:00 to 04:59 at weekends.
+    pub fn cron(mut self, expression: &str) -> Result<Self, CronError> {
+        self.windows.push(Window::Cron(Cron::parse(expression)?));
+        Ok(self)
+    }
+}
+impl Guard for TimeWindow {
+    fn check_guard(&self, expected: &Self) -> bool {
+        self.now.is_some_and(|now| expected.windows.iter().any(|window| window.contains(&now)))
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        GuardDenial::status(StatusCode::FORBIDDEN)
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<ScheduleConfig> for TimeWindow {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(_parts: &mut Parts, state: &ScheduleConfig) -> Result<Self, Self::Rejection> {
+        let now = LocalTime::new(state.clock.now(), state.offset_minutes);
+        Ok(Self { now: Some(now), windows: Vec::new() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+    use super::Weekday::*;
+
+    // 2024-03-02 was a Saturday.
+    fn at(day: i64, hour: i64, minute: i64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs((1_709_337_600 + (day - 2) * 86_400 + hour * 3600 + minute * 60) as u64)
+    }
+
+    #[test]
+    fn test_local_time() {
+        assert_eq!(LocalTime::new(at(2, 13, 5), 0), LocalTime { minute: 5, hour: 13, day: 2, month: 3, weekday: 6 });
+        assert_eq!(LocalTime::new(at(2, 1, 0), -120), LocalTime { minute: 0, hour: 23, day: 1, month: 3, weekday: 5 });
+        assert_eq!(LocalTime::new(UNIX_EPOCH, 0), LocalTime { minute: 0, hour: 0, day: 1, month: 1, weekday: 4 });
+    }
+
+    #[test]
+    fn test_weekly_window() {
+        let overnight = TimeWindow::new().weekly([Fri, Sat], (22, 0), (2, 0)).unwrap();
+        let check = |time| TimeWindow { now: Some(LocalTime::new(time, 0)), windows: Vec::new() }.check_guard(&overnight);
+        assert!(check(at(1, 23, 0)));
+        assert!(check(at(2, 1, 59)));
+        assert!(!check(at(2, 2, 0)));
+        assert!(check(at(3, 0, 30)));
+        assert!(!check(at(3, 22, 0)));
+        assert!(!check(at(1, 21, 59)));
+        assert_eq!(TimeWindow::new().weekly([Mon], (200, 0), (2, 0)), Err(TimeOfDayError(200, 0)));
+        assert_eq!(TimeWindow::new().weekly([Mon], (9, 0), (17, 60)), Err(TimeOfDayError(17, 60)));
+    }
+
+    #[test]
+    fn test_cron() {
+        let cron = Cron::parse("*/15 2-4 * * sat,7").unwrap();
+        assert!(cron.matches(&LocalTime::new(at(2, 3, 45), 0)));
+        assert!(!cron.matches(&LocalTime::new(at(2, 3, 46), 0)));
+        assert!(cron.matches(&LocalTime::new(at(3, 2, 0), 0)));
+        assert!(!cron.matches(&LocalTime::new(at(4, 2, 0), 0)));
+        let first_or_monday = Cron::parse("* * 1 * mon").unwrap();
+        assert!(first_or_monday.matches(&LocalTime::new(at(1, 0, 0), 0)));
+        assert!(first_or_monday.matches(&LocalTime::new(at(4, 0, 0), 0)));
+        let odd_mondays = Cron::parse("0 9 */2 * 1").unwrap();
+        assert!(odd_mondays.matches(&LocalTime::new(at(11, 9, 0), 0)));
+        assert!(!odd_mondays.matches(&LocalTime::new(at(4, 9, 0), 0)));
+        assert!(!odd_mondays.matches(&LocalTime::new(at(3, 9, 0), 0)));
+        assert!(Cron::parse("* * * *").is_err());
+        assert!(Cron::parse("60 * * * *").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_guard_with_clock() {
+        let clock = ManualClock::new(at(2, 1, 30));
+        let config = ScheduleConfig::new().clock(clock.clone()).utc_offset(120);
+        let window = TimeWindow::new().cron("* 2-3 * * sat").unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(GuardService::new(config, window, "outside the maintenance window").into_layer());
+        let status = || async { app.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap().status() };
+        assert_eq!(status().await, StatusCode::OK);
+        clock.advance(Duration::from_secs(3 * 3600));
+        assert_eq!(status().await, StatusCode::FORBIDDEN);
+    }
+}
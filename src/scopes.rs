@@ -0,0 +1,163 @@
+//! OAuth style permission scopes such as `repo:read`, with `*` segment wildcards.
+use std::sync::Arc;
+use axum_core::extract::FromRequestParts;
+use http::StatusCode;
+use http::request::Parts;
+use crate::{Guard, GuardDenial};
+
+type ScopeSource = Arc<dyn Fn(&Parts) -> Vec<String> + Send + Sync>;
+
+/// The state passed to `GuardService::new`: how scopes are split into segments
+/// and how to find the scopes granted to the caller.
+#[derive(Clone)]
+pub struct ScopesConfig {
+    separator: char,
+    source: ScopeSource,
+}
+impl ScopesConfig {
+    /// Segments are separated by `:`.
+    pub fn new(source: impl Fn(&Parts) -> Vec<String> + Send + Sync + 'static) -> Self {
+        Self { separator: ':', source: Arc::new(source) }
+    }
+    pub fn separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    All,
+    Any,
+}
+
+/// Extracted: the scopes granted to the caller.
+/// Expected: the scopes the route requires, all of them or any one.
+///
+/// A granted `*` segment stands for any single segment, and a trailing `*` for any
+/// number of remaining ones, so `repo:*` covers `repo:read` and `repo:read:private`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Scopes {
+    scopes: Vec<String>,
+    mode: Mode,
+    separator: char,
+}
+impl Scopes {
+    pub fn all<I, T>(scopes: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<String>, {
+        Self { scopes: scopes.into_iter().map(Into::into).collect(), mode: Mode::All, separator: ':' }
+    }
+    pub fn any_of<I, T>(scopes: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<String>, {
+        Self { scopes: scopes.into_iter().map(Into::into).collect(), mode: Mode::Any, separator: ':' }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.scopes.iter().map(String::as_str)
+    }
+    /// Whether any granted scope covers `required`.
+    pub fn covers(&self, required: &str) -> bool {
+        self.scopes.iter().any(|granted| covers(granted, required, self.separator))
+    }
+}
+
+fn covers(granted: &str, required: &str, separator: char) -> bool {
+    let granted: Vec<&str> = granted.split(separator).collect();
+    let required: Vec<&str> = required.split(separator).collect();
+    for (i, segment) in granted.iter().enumerate() {
+        let last = i == granted.len() - 1;
+        match required.get(i) {
+            None => return false,
+            Some(_) if *segment == "*" && last => return true,
+            Some(required) if *segment == "*" || segment == required => {}
+            Some(_) => return false,
+        }
+    }
+    granted.len() == required.len()
+}
+
+impl Guard for Scopes {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match expected.mode {
+            Mode::All => expected.scopes.iter().all(|required| self.covers(required)),
+            Mode::Any => expected.scopes.iter().any(|required| self.covers(required)),
+        }
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        if self.scopes.is_empty() {
+            GuardDenial::default()
+        } else {
+            GuardDenial::status(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<ScopesConfig> for Scopes {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &ScopesConfig) -> Result<Self, Self::Rejection> {
+        Ok(Self { scopes: (state.source)(parts), mode: Mode::All, separator: state.separator })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    async fn ok() -> StatusCode {
+        StatusCode::OK
+    }
+    fn from_header(parts: &Parts) -> Vec<String> {
+        parts.headers.get("x-scope")
+            .and_then(|scope| scope.to_str().ok())
+            .map(|scope| scope.split_whitespace().map(String::from).collect())
+            .unwrap_or_default()
+    }
+    async fn status(config: ScopesConfig, expected: Scopes, granted: &'static str) -> StatusCode {
+        let app = Router::new()
+            .route("/", get(ok))
+            .layer(GuardService::new(config, expected, "missing scope").into_layer());
+        let req = Request::get("/").header("x-scope", granted).body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(covers("repo:read", "repo:read", ':'));
+        assert!(covers("repo:*", "repo:read", ':'));
+        assert!(covers("repo:*", "repo:read:private", ':'));
+        assert!(covers("admin:*:write", "admin:org:write", ':'));
+        assert!(!covers("admin:*:write", "admin:org:read", ':'));
+        assert!(!covers("admin:*:write", "admin:org", ':'));
+        assert!(!covers("repo:read", "repo", ':'));
+        assert!(!covers("repo", "repo:read", ':'));
+        assert!(!covers("repo:*", "repo", ':'));
+    }
+
+    #[tokio::test]
+    async fn test_all_and_any() {
+        let config = || ScopesConfig::new(from_header);
+        let all = || Scopes::all(["repo:read", "admin:org:write"]);
+        assert_eq!(status(config(), all(), "repo:* admin:*:write").await, StatusCode::OK);
+        assert_eq!(status(config(), all(), "repo:*").await, StatusCode::FORBIDDEN);
+        let any = Scopes::any_of(["repo:read", "admin:org:write"]);
+        assert_eq!(status(config(), any, "repo:read").await, StatusCode::OK);
+        assert_eq!(status(config(), all(), "").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_separator() {
+        let config = ScopesConfig::new(from_header).separator('.');
+        assert_eq!(status(config, Scopes::all(["repo.read"]), "repo.*").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,408 @@
+//! Cookie sessions: the cookie carries a signed or encrypted session id, the data lives
+//! in a [`SessionStore`], and [`Session`] guards routes on it like any header credential.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use axum_core::extract::FromRequestParts;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use hmac::digest::KeyInit;
+use http::StatusCode;
+use http::request::Parts;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use sha2::Sha256;
+use crate::{cookie, Guard, GuardDenial};
+use crate::schedule::{Clock, SystemClock};
+
+#[derive(Clone)]
+enum Protection {
+    Signed(Arc<Vec<u8>>),
+    Encrypted(Arc<LessSafeKey>),
+}
+
+/// How cookie values are protected. Either way the cookie name is bound in, so a value
+/// can't be replayed under another cookie.
+#[derive(Clone)]
+pub struct CookieCodec(Protection);
+impl CookieCodec {
+    /// Readable by the client, tamper evident with HMAC-SHA256.
+    pub fn signed(secret: &[u8]) -> Self {
+        Self(Protection::Signed(Arc::new(secret.to_vec())))
+    }
+    /// Opaque to the client, with ChaCha20-Poly1305.
+    pub fn encrypted(key: &[u8; 32]) -> Self {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, key).expect("a 32 byte key fits ChaCha20-Poly1305");
+        Self(Protection::Encrypted(Arc::new(LessSafeKey::new(key))))
+    }
+    fn mac(secret: &[u8], name: &str, value: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(secret).expect("hmac takes keys of any length");
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value.as_bytes());
+        mac
+    }
+    pub fn encode(&self, name: &str, value: &str) -> String {
+        match &self.0 {
+            Protection::Signed(secret) => {
+                let tag = Self::mac(secret, name, value).finalize().into_bytes();
+                format!("{}.{}", URL_SAFE_NO_PAD.encode(value), URL_SAFE_NO_PAD.encode(tag))
+            }
+            Protection::Encrypted(key) => {
+                let mut nonce = [0u8; NONCE_LEN];
+                getrandom::getrandom(&mut nonce).expect("the system random source is available");
+                let mut sealed = value.as_bytes().to_vec();
+                key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(name.as_bytes()), &mut sealed)
+                    .expect("cookie values are far below the cipher's limit");
+                URL_SAFE_NO_PAD.encode([&nonce[..], &sealed].concat())
+            }
+        }
+    }
+    /// The value, if it was encoded by this codec for this cookie.
+    pub fn decode(&self, name: &str, encoded: &str) -> Option<String> {
+        match &self.0 {
+            Protection::Signed(secret) => {
+                let (value, tag) = encoded.split_once('.')?;
+                let value = String::from_utf8(URL_SAFE_NO_PAD.decode(value).ok()?).ok()?;
+                let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+                Self::mac(secret, name, &value).verify_slice(&tag).ok()?;
+                Some(value)
+            }
+            Protection::Encrypted(key) => {
+                let sealed = URL_SAFE_NO_PAD.decode(encoded).ok()?;
+                if sealed.len() < NONCE_LEN {
+                    return None;
+                }
+                let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+                let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+                let mut sealed = sealed.to_vec();
+                let value = key.open_in_place(nonce, Aad::from(name.as_bytes()), &mut sealed).ok()?;
+                String::from_utf8(value.to_vec()).ok()
+            }
+        }
+    }
+}
+
+/// A stored session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionRecord<T> {
+    pub data: T,
+    pub created: SystemTime,
+    pub last_seen: SystemTime,
+    /// When the session expires unless it is used again, for stores to drop it by.
+    /// `None` without an idle or absolute timeout.
+    pub expires: Option<SystemTime>,
+}
+
+/// Where session data lives. Store errors reject the request with `503`.
+#[async_trait::async_trait]
+pub trait SessionStore<T>: Send + Sync + 'static {
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord<T>>, String>;
+    async fn save(&self, id: &str, record: SessionRecord<T>) -> Result<(), String>;
+    /// Sets `last_seen` and `expires` of a stored session in one step, leaving its data as
+    /// it is. `false` if the session is gone, e.g. removed by a concurrent logout.
+    async fn touch(&self, id: &str, last_seen: SystemTime, expires: Option<SystemTime>) -> Result<bool, String>;
+    async fn remove(&self, id: &str) -> Result<(), String>;
+}
+
+/// Sessions in this process only, lost on restart. Expired sessions are swept out as new
+/// ones are saved.
+pub struct MemoryStore<T> {
+    clock: Arc<dyn Clock>,
+    sessions: Mutex<Sessions<T>>,
+}
+struct Sessions<T> {
+    records: HashMap<String, SessionRecord<T>>,
+    sweep_at: usize,
+}
+impl<T> Default for MemoryStore<T> {
+    fn default() -> Self {
+        Self { clock: Arc::new(SystemClock), sessions: Mutex::new(Sessions { records: HashMap::new(), sweep_at: 0 }) }
+    }
+}
+impl<T> MemoryStore<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The clock expiry is swept by, the same as the [`SessionConfig`]'s.
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    pub fn len(&self) -> usize {
+        self.sessions.lock().map(|sessions| sessions.records.len()).unwrap_or_default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+#[async_trait::async_trait]
+impl<T: Clone + Send + Sync + 'static> SessionStore<T> for MemoryStore<T> {
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord<T>>, String> {
+        let sessions = self.sessions.lock().map_err(|_| String::from("session store poisoned"))?;
+        Ok(sessions.records.get(id).cloned())
+    }
+    async fn save(&self, id: &str, record: SessionRecord<T>) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|_| String::from("session store poisoned"))?;
+        if sessions.records.len() >= sessions.sweep_at {
+            let now = self.clock.now();
+            sessions.records.retain(|_, record| record.expires.is_none_or(|expires| expires > now));
+            sessions.sweep_at = (sessions.records.len() * 2).max(1024);
+        }
+        sessions.records.insert(id.to_string(), record);
+        Ok(())
+    }
+    async fn touch(&self, id: &str, last_seen: SystemTime, expires: Option<SystemTime>) -> Result<bool, String> {
+        let mut sessions = self.sessions.lock().map_err(|_| String::from("session store poisoned"))?;
+        let Some(record) = sessions.records.get_mut(id) else {
+            return Ok(false);
+        };
+        record.last_seen = last_seen;
+        record.expires = expires;
+        Ok(true)
+    }
+    async fn remove(&self, id: &str) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|_| String::from("session store poisoned"))?;
+        sessions.records.remove(id);
+        Ok(())
+    }
+}
+
+fn store_error(err: String) -> (StatusCode, String) {
+    (StatusCode::SERVICE_UNAVAILABLE, err)
+}
+
+/// The state passed to `GuardService::new`: the cookie, its codec, the store and when
+/// sessions expire. No expiry is set by default.
+pub struct SessionConfig<T> {
+    name: &'static str,
+    codec: CookieCodec,
+    store: Arc<dyn SessionStore<T>>,
+    idle: Option<Duration>,
+    absolute: Option<Duration>,
+    clock: Arc<dyn Clock>,
+}
+impl<T> Clone for SessionConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            codec: self.codec.clone(),
+            store: self.store.clone(),
+            idle: self.idle,
+            absolute: self.absolute,
+            clock: self.clock.clone(),
+        }
+    }
+}
+impl<T: Send + Sync + 'static> SessionConfig<T> {
+    pub fn new(codec: CookieCodec, store: Arc<dyn SessionStore<T>>) -> Self {
+        Self { name: "session", codec, store, idle: None, absolute: None, clock: Arc::new(SystemClock) }
+    }
+    pub fn cookie_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+    /// Expire sessions unused for this long; each request resets the timer.
+    pub fn idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+    /// Expire sessions this long after they were created, however active.
+    pub fn absolute_timeout(mut self, absolute: Duration) -> Self {
+        self.absolute = Some(absolute);
+        self
+    }
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+    /// Stores a new session and returns the `Set-Cookie` value carrying its id.
+    pub async fn create(&self, data: T) -> Result<String, (StatusCode, String)> {
+        let mut id = [0u8; 32];
+        getrandom::getrandom(&mut id).expect("the system random source is available");
+        let id = URL_SAFE_NO_PAD.encode(id);
+        let now = self.clock.now();
+        let record = SessionRecord { data, created: now, last_seen: now, expires: self.expires(now, now) };
+        self.store.save(&id, record).await.map_err(store_error)?;
+        Ok(format!("{}={}; Path=/; HttpOnly; Secure; SameSite=Lax", self.name, self.codec.encode(self.name, &id)))
+    }
+    /// Removes the session and returns the `Set-Cookie` value clearing it.
+    pub async fn destroy(&self, session: &Session<T>) -> Result<String, (StatusCode, String)> {
+        if let Some(id) = &session.id {
+            self.store.remove(id).await.map_err(store_error)?;
+        }
+        Ok(format!("{}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Lax", self.name))
+    }
+    fn expires(&self, created: SystemTime, last_seen: SystemTime) -> Option<SystemTime> {
+        let absolute = self.absolute.map(|absolute| created + absolute);
+        let idle = self.idle.map(|idle| last_seen + idle);
+        absolute.into_iter().chain(idle).min()
+    }
+    fn expired(&self, record: &SessionRecord<T>, now: SystemTime) -> bool {
+        let older_than = |since: SystemTime, limit: Option<Duration>| {
+            limit.is_some_and(|limit| now.duration_since(since).unwrap_or_default() >= limit)
+        };
+        older_than(record.created, self.absolute) || older_than(record.last_seen, self.idle)
+    }
+}
+
+/// Extracted: the session the cookie refers to, if it is valid and current.
+/// Expected: [`Session::any`] for any session, or [`Session::matching`] to check the
+/// session data as a guard of its own, e.g. `Session::matching(Roles::all(["admin"]))`.
+///
+/// Without a session it fails with `401`, with the wrong data as the data's guard would.
+/// On success the session is inserted into the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session<T> {
+    id: Option<String>,
+    data: Option<T>,
+}
+impl<T> Session<T> {
+    pub fn any() -> Self {
+        Self { id: None, data: None }
+    }
+    pub fn matching(data: T) -> Self {
+        Self { id: None, data: Some(data) }
+    }
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+    pub fn data(&self) -> Option<&T> {
+        self.data.as_ref()
+    }
+}
+impl<T> Guard for Session<T>
+    where
+        T: Guard + Clone + Send + Sync + 'static, {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match (&self.data, &expected.data) {
+            (Some(data), Some(expected)) => data.check_guard(expected),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+    fn denial(&self, expected: &Self) -> GuardDenial {
+        match (&self.data, &expected.data) {
+            (Some(data), Some(expected)) => data.denial(expected),
+            _ => GuardDenial::default(),
+        }
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        parts.extensions.insert(self);
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> FromRequestParts<SessionConfig<T>> for Session<T>
+    where
+        T: Clone + Send + Sync + 'static, {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &SessionConfig<T>) -> Result<Self, Self::Rejection> {
+        let Some(id) = cookie(parts, state.name).and_then(|value| state.codec.decode(state.name, &value)) else {
+            return Ok(Self::any());
+        };
+        let Some(record) = state.store.load(&id).await.map_err(store_error)? else {
+            return Ok(Self::any());
+        };
+        let now = state.clock.now();
+        if state.expired(&record, now) {
+            state.store.remove(&id).await.map_err(store_error)?;
+            return Ok(Self::any());
+        }
+        if state.idle.is_some() {
+            let expires = state.expires(record.created, now);
+            if !state.store.touch(&id, now, expires).await.map_err(store_error)? {
+                return Ok(Self::any());
+            }
+        }
+        Ok(Self { id: Some(id), data: Some(record.data) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use crate::rbac::Roles;
+    use crate::schedule::ManualClock;
+    use super::*;
+
+    #[test]
+    fn test_codecs() {
+        for codec in [CookieCodec::signed(b"secret"), CookieCodec::encrypted(&[7; 32])] {
+            let encoded = codec.encode("session", "abc");
+            assert_eq!(codec.decode("session", &encoded).as_deref(), Some("abc"));
+            assert_eq!(codec.decode("other", &encoded), None);
+            let mut tampered = encoded.clone().into_bytes();
+            tampered[2] ^= 1;
+            assert_eq!(codec.decode("session", &String::from_utf8(tampered).unwrap()), None);
+        }
+        assert!(!CookieCodec::encrypted(&[7; 32]).encode("session", "abc").contains("YWJj"));
+        assert_eq!(CookieCodec::signed(b"other").decode("session", &CookieCodec::signed(b"secret").encode("session", "abc")), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_guard() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let store = Arc::new(MemoryStore::new().clock(clock.clone()));
+        let config = SessionConfig::new(CookieCodec::encrypted(&[1; 32]), store.clone())
+            .idle_timeout(Duration::from_secs(600))
+            .absolute_timeout(Duration::from_secs(3600))
+            .clock(clock.clone());
+        let app = Router::new()
+            .route("/", get(|| async { StatusCode::OK }))
+            .layer(GuardService::new(config.clone(), Session::matching(Roles::all(["admin"])), "admins only").into_layer());
+        let cookie = |set_cookie: String| set_cookie.split(';').next().unwrap().to_string();
+        let admin = cookie(config.create(Roles::all(["admin"])).await.unwrap());
+        let viewer = cookie(config.create(Roles::all(["viewer"])).await.unwrap());
+        let status = |cookie: String| {
+            let app = app.clone();
+            async move { app.oneshot(Request::get("/").header("cookie", cookie).body(Body::empty()).unwrap()).await.unwrap().status() }
+        };
+        assert_eq!(status(admin.clone()).await, StatusCode::OK);
+        assert_eq!(status(viewer).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(String::from("session=forged")).await, StatusCode::UNAUTHORIZED);
+        for _ in 0..7 {
+            clock.advance(Duration::from_secs(500));
+            assert_eq!(status(admin.clone()).await, StatusCode::OK);
+        }
+        clock.advance(Duration::from_secs(500));
+        assert_eq!(status(admin).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(store.len(), 1);
+
+        let idle = cookie(config.create(Roles::all(["admin"])).await.unwrap());
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(status(idle).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let store = Arc::new(MemoryStore::new().clock(clock.clone()));
+        let config = SessionConfig::new(CookieCodec::signed(b"secret"), store.clone())
+            .idle_timeout(Duration::from_secs(600))
+            .clock(clock.clone());
+        for _ in 0..1024 {
+            config.create(()).await.unwrap();
+        }
+        clock.advance(Duration::from_secs(600));
+        config.create(()).await.unwrap();
+        assert_eq!(store.len(), 1);
+
+        // Refreshing a session removed in the meantime doesn't bring it back.
+        let now = clock.now();
+        let record = SessionRecord { data: (), created: now, last_seen: now, expires: None };
+        store.save("a", record.clone()).await.unwrap();
+        assert!(store.touch("a", now + Duration::from_secs(1), None).await.unwrap());
+        assert_eq!(store.load("a").await.unwrap().map(|record| record.last_seen), Some(now + Duration::from_secs(1)));
+        store.remove("a").await.unwrap();
+        assert!(!store.touch("a", now, None).await.unwrap());
+        assert_eq!(store.load("a").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Multi-tenant isolation: the tenant a request addresses must be one the caller belongs to.
+use std::sync::Arc;
+use axum::extract::RawPathParams;
+use axum_core::extract::FromRequestParts;
+use http::StatusCode;
+use http::request::Parts;
+use crate::{Guard, GuardDenial};
+
+/// Where the request names its tenant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TenantSource {
+    /// The label just below this domain: `acme` in `acme.example.com` or `www.acme.example.com`.
+    Subdomain(String),
+    Header(String),
+    /// A named route parameter, only known when the guard is applied with `route_layer`.
+    PathParam(String),
+    /// The path segment at this index, `0` being `acme` in `/acme/orders`.
+    PathSegment(usize),
+}
+impl TenantSource {
+    fn resolve(&self, parts: &Parts, params: &[(String, String)]) -> Option<String> {
+        match self {
+            TenantSource::Subdomain(domain) => {
+                let host = crate::predicate::host(parts)?;
+                let sub = host.strip_suffix(domain.as_str())?.strip_suffix('.')?;
+                sub.rsplit('.').next().map(String::from)
+            }
+            TenantSource::Header(name) => parts.headers.get(name.as_str())?.to_str().ok().map(String::from),
+            TenantSource::PathParam(name) => params.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone()),
+            TenantSource::PathSegment(index) => parts.uri.path().split('/').filter(|s| !s.is_empty()).nth(*index).map(String::from),
+        }
+        .filter(|tenant| !tenant.is_empty())
+    }
+}
+
+type TenantsOf = Arc<dyn Fn(&Parts) -> Vec<String> + Send + Sync>;
+
+/// The state passed to `GuardService::new`: where to find the request tenant, tried in
+/// order, and the tenants the caller belongs to, usually read from the claims an earlier
+/// guard put in the extensions.
+#[derive(Clone)]
+pub struct TenantConfig {
+    sources: Vec<TenantSource>,
+    tenants_of: TenantsOf,
+}
+impl TenantConfig {
+    pub fn new(tenants_of: impl Fn(&Parts) -> Vec<String> + Send + Sync + 'static) -> Self {
+        Self { sources: Vec::new(), tenants_of: Arc::new(tenants_of) }
+    }
+    pub fn source(mut self, source: TenantSource) -> Self {
+        self.sources.push(source);
+        self
+    }
+}
+
+/// The tenant a passing request addresses, inserted into the request extensions.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TenantId(pub String);
+
+/// Extracted: the request tenant and the caller's tenants.
+/// Expected: [`Tenant::member`], the request tenant must be one of the caller's.
+///
+/// A caller without tenants fails with `401`. An unknown or foreign tenant is a `404`,
+/// so probing does not reveal which tenants exist.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tenant {
+    tenant: Option<String>,
+    tenants: Vec<String>,
+}
+impl Tenant {
+    pub fn member() -> Self {
+        Self::default()
+    }
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+}
+impl Guard for Tenant {
+    fn check_guard(&self, _expected: &Self) -> bool {
+        self.tenant.as_ref().is_some_and(|tenant| self.tenants.contains(tenant))
+    }
+    fn denial(&self, _expected: &Self) -> GuardDenial {
+        if self.tenants.is_empty() {
+            GuardDenial::default()
+        } else {
+            GuardDenial::status(StatusCode::NOT_FOUND)
+        }
+    }
+    fn on_pass(self, parts: &mut Parts) {
+        if let Some(tenant) = self.tenant {
+            parts.extensions.insert(TenantId(tenant));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<TenantConfig> for Tenant {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &TenantConfig) -> Result<Self, Self::Rejection> {
+        let params = match RawPathParams::from_request_parts(parts, state).await {
+            Ok(params) => params.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            Err(_) => Vec::new(),
+        };
+        let tenant = state.sources.iter().find_map(|source| source.resolve(parts, &params));
+        Ok(Self { tenant, tenants: (state.tenants_of)(parts) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::extract::Extension;
+    use axum::routing::get;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    fn config() -> TenantConfig {
+        TenantConfig::new(|parts| {
+            parts.headers.get("x-tenants")
+                .and_then(|tenants| tenants.to_str().ok())
+                .map(|tenants| tenants.split(',').map(String::from).collect())
+                .unwrap_or_default()
+        })
+    }
+    async fn status(app: &Router, path: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let req = headers.iter()
+            .fold(Request::get(path), |req, (name, value)| req.header(*name, *value))
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_subdomain_then_header() {
+        let config = config().source(TenantSource::Subdomain("example.com".into())).source(TenantSource::Header("x-tenant".into()));
+        let app = Router::new()
+            .route("/", get(|Extension(TenantId(tenant)): Extension<TenantId>| async move { tenant }))
+            .layer(GuardService::new(config, Tenant::member(), "unknown tenant").into_layer());
+        let resp = app.clone()
+            .oneshot(Request::get("/").header("host", "Acme.example.com").header("x-tenants", "acme").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(&hyper::body::to_bytes(resp.into_body()).await.unwrap()[..], b"acme");
+        assert_eq!(status(&app, "/", &[("host", "globex.example.com"), ("x-tenants", "acme")]).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/", &[("host", "example.com"), ("x-tenant", "acme"), ("x-tenants", "acme")]).await, StatusCode::OK);
+        assert_eq!(status(&app, "/", &[("host", "example.com"), ("x-tenants", "acme")]).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/", &[("host", "acme.example.com")]).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_path_sources() {
+        let app = Router::new()
+            .route("/t/:tenant/orders", get(|| async { StatusCode::OK }))
+            .route_layer(GuardService::new(config().source(TenantSource::PathParam("tenant".into())), Tenant::member(), "unknown tenant").into_layer());
+        assert_eq!(status(&app, "/t/acme/orders", &[("x-tenants", "globex,acme")]).await, StatusCode::OK);
+        assert_eq!(status(&app, "/t/initech/orders", &[("x-tenants", "globex,acme")]).await, StatusCode::NOT_FOUND);
+        let app = Router::new()
+            .route("/:tenant/orders", get(|| async { StatusCode::OK }))
+            .layer(GuardService::new(config().source(TenantSource::PathSegment(0)), Tenant::member(), "unknown tenant").into_layer());
+        assert_eq!(status(&app, "/acme/orders", &[("x-tenants", "acme")]).await, StatusCode::OK);
+        assert_eq!(status(&app, "/globex/orders", &[("x-tenants", "acme")]).await, StatusCode::NOT_FOUND);
+    }
+}
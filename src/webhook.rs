@@ -0,0 +1,301 @@
+//! HMAC signature verification for inbound webhooks.
+//!
+//! The signature is computed over the raw body, so the guard has to run behind
+//! [`crate::GuardServiceExt::into_buffered_layer`] (or a [`crate::body::BufferBodyLayer`]).
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use axum_core::extract::FromRequestParts;
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use hmac::digest::KeyInit;
+use http::{HeaderName, StatusCode};
+use http::request::Parts;
+use sha1::Sha1;
+use sha2::Sha256;
+use crate::Guard;
+use crate::body::BufferedBody;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Sha256,
+    Sha1,
+}
+impl SignatureAlgorithm {
+    fn prefix(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Sha256 => "sha256",
+            SignatureAlgorithm::Sha1 => "sha1",
+        }
+    }
+    fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix.to_ascii_lowercase().as_str() {
+            "sha256" => Some(SignatureAlgorithm::Sha256),
+            "sha1" => Some(SignatureAlgorithm::Sha1),
+            _ => None,
+        }
+    }
+    fn digest(&self, secret: &[u8], payload: &[u8]) -> Vec<u8> {
+        match self {
+            SignatureAlgorithm::Sha256 => keyed::<Hmac<Sha256>>(secret, payload).finalize().into_bytes().to_vec(),
+            SignatureAlgorithm::Sha1 => keyed::<Hmac<Sha1>>(secret, payload).finalize().into_bytes().to_vec(),
+        }
+    }
+    /// Constant-time comparison of `signature` against the HMAC of `payload`.
+    fn verify(&self, secret: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+        match self {
+            SignatureAlgorithm::Sha256 => keyed::<Hmac<Sha256>>(secret, payload).verify_slice(signature).is_ok(),
+            SignatureAlgorithm::Sha1 => keyed::<Hmac<Sha1>>(secret, payload).verify_slice(signature).is_ok(),
+        }
+    }
+}
+
+fn keyed<M: Mac + KeyInit>(secret: &[u8], payload: &[u8]) -> M {
+    let mut mac = <M as KeyInit>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(payload);
+    mac
+}
+
+/// Where the signature lives and how old a signed request may be.
+/// Used as the state passed to `GuardService::new`.
+///
+/// By default the signature must cover an `X-Signature-Timestamp` within five minutes,
+/// so a captured request can't be replayed later. Senders that sign only the body
+/// need [`WebhookConfig::without_timestamp`].
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    signature_header: HeaderName,
+    timestamp_header: Option<HeaderName>,
+    tolerance: Duration,
+    algorithms: Vec<SignatureAlgorithm>,
+}
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            signature_header: HeaderName::from_static("x-signature"),
+            timestamp_header: Some(HeaderName::from_static("x-signature-timestamp")),
+            tolerance: Duration::from_secs(300),
+            algorithms: vec![SignatureAlgorithm::Sha256],
+        }
+    }
+}
+impl WebhookConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn signature_header(mut self, header: HeaderName) -> Self {
+        self.signature_header = header;
+        self
+    }
+    /// The header must carry a unix timestamp within `tolerance` of now
+    /// and the signed payload is `"{timestamp}.{body}"`.
+    pub fn timestamp_header(mut self, header: HeaderName) -> Self {
+        self.timestamp_header = Some(header);
+        self
+    }
+    /// Verifies the body alone. **Replay protection is off:** anyone who captures a
+    /// signed request can resend it for as long as the secret is valid, so the handler
+    /// has to be idempotent or deduplicate deliveries itself.
+    pub fn without_timestamp(mut self) -> Self {
+        self.timestamp_header = None;
+        self
+    }
+    pub fn tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+    pub fn algorithms(mut self, algorithms: impl IntoIterator<Item = SignatureAlgorithm>) -> Self {
+        self.algorithms = algorithms.into_iter().collect();
+        self
+    }
+}
+
+/// Extracted: the signatures sent with the request and the payload they cover.
+/// Expected: the secrets currently accepted, several at once while rotating.
+#[derive(Clone)]
+pub struct WebhookSignature {
+    kind: Kind,
+}
+#[derive(Clone)]
+enum Kind {
+    Received {
+        signatures: Vec<(SignatureAlgorithm, Vec<u8>)>,
+        payload: Bytes,
+        fresh: bool,
+    },
+    Expected {
+        secrets: Arc<[Vec<u8>]>,
+    },
+}
+impl WebhookSignature {
+    pub fn secrets<I, T>(secrets: I) -> Self
+        where
+            I: IntoIterator<Item = T>,
+            T: AsRef<[u8]>, {
+        Self {
+            kind: Kind::Expected {
+                secrets: secrets.into_iter().map(|secret| secret.as_ref().to_vec()).collect(),
+            }
+        }
+    }
+    /// Builds the header value a sender would attach, e.g. `sha256=5d41...`.
+    pub fn sign(algorithm: SignatureAlgorithm, secret: &[u8], timestamp: Option<u64>, body: &[u8]) -> String {
+        let payload = signed_payload(timestamp, body);
+        format!("{}={}", algorithm.prefix(), hex::encode(algorithm.digest(secret, &payload)))
+    }
+}
+impl Guard for WebhookSignature {
+    fn check_guard(&self, expected: &Self) -> bool {
+        match (&self.kind, &expected.kind) {
+            (Kind::Received { signatures, payload, fresh }, Kind::Expected { secrets }) => {
+                *fresh && signatures.iter().any(|(algorithm, signature)| {
+                    secrets.iter().any(|secret| algorithm.verify(secret, payload, signature))
+                })
+            }
+            _ => false,
+        }
+    }
+}
+
+fn signed_payload(timestamp: Option<u64>, body: &[u8]) -> Bytes {
+    match timestamp {
+        Some(timestamp) => {
+            let mut buf = BytesMut::new();
+            buf.put_slice(timestamp.to_string().as_bytes());
+            buf.put_u8(b'.');
+            buf.put_slice(body);
+            buf.freeze()
+        }
+        None => Bytes::copy_from_slice(body),
+    }
+}
+
+fn parse_signatures(value: &str, allowed: &[SignatureAlgorithm]) -> Vec<(SignatureAlgorithm, Vec<u8>)> {
+    value.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|entry| entry.split_once('='))
+        .filter_map(|(prefix, sig)| {
+            let algorithm = SignatureAlgorithm::from_prefix(prefix.trim())?;
+            if !allowed.contains(&algorithm) {
+                return None;
+            }
+            Some((algorithm, hex::decode(sig.trim()).ok()?))
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl FromRequestParts<WebhookConfig> for WebhookSignature {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &WebhookConfig) -> Result<Self, Self::Rejection> {
+        let body = parts.extensions.get::<BufferedBody>()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "request body was not buffered".into()))?
+            .0.clone();
+        let signatures = parts.headers.get(&state.signature_header)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| parse_signatures(value, &state.algorithms))
+            .unwrap_or_default();
+        let (timestamp, fresh) = match &state.timestamp_header {
+            Some(header) => {
+                let timestamp = parts.headers.get(header)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse::<u64>().ok());
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                let fresh = timestamp
+                    .is_some_and(|timestamp| now.abs_diff(timestamp) <= state.tolerance.as_secs());
+                (timestamp, fresh)
+            }
+            None => (None, true),
+        };
+        Ok(Self {
+            kind: Kind::Received {
+                signatures,
+                payload: signed_payload(timestamp, &body),
+                fresh,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::Router;
+    use axum::routing::post;
+    use http::Request;
+    use tower::util::ServiceExt;
+    use crate::{GuardService, GuardServiceExt};
+    use super::*;
+
+    async fn ok() -> StatusCode {
+        StatusCode::OK
+    }
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+    fn app(config: WebhookConfig) -> Router {
+        Router::new()
+            .route("/", post(ok))
+            .layer(
+                GuardService::new(config, WebhookSignature::secrets(["old", "new"]), "bad signature")
+                    .into_buffered_layer(1024)
+            )
+    }
+    fn request(signature: String, timestamp: Option<u64>, body: impl Into<Body>) -> Request<Body> {
+        let mut builder = Request::post("/").header("x-signature", signature);
+        if let Some(timestamp) = timestamp {
+            builder = builder.header("x-signature-timestamp", timestamp.to_string());
+        }
+        builder.body(body.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_valid_signature_with_rotated_secret() {
+        let signature = WebhookSignature::sign(SignatureAlgorithm::Sha256, b"old", None, b"payload");
+        let resp = app(WebhookConfig::new().without_timestamp()).oneshot(request(signature, None, "payload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_tampered_body() {
+        let signature = WebhookSignature::sign(SignatureAlgorithm::Sha256, b"new", None, b"payload");
+        let resp = app(WebhookConfig::new().without_timestamp()).oneshot(request(signature, None, "payl0ad")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_algorithm() {
+        let signature = WebhookSignature::sign(SignatureAlgorithm::Sha1, b"new", None, b"payload");
+        let resp = app(WebhookConfig::new().without_timestamp()).oneshot(request(signature.clone(), None, "payload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let config = WebhookConfig::new()
+            .without_timestamp()
+            .algorithms([SignatureAlgorithm::Sha256, SignatureAlgorithm::Sha1]);
+        let resp = app(config).oneshot(request(signature, None, "payload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_window() {
+        let config = WebhookConfig::new().tolerance(Duration::from_secs(60));
+        let fresh = now();
+        let signature = WebhookSignature::sign(SignatureAlgorithm::Sha256, b"new", Some(fresh), b"payload");
+        let resp = app(config.clone()).oneshot(request(signature, Some(fresh), "payload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let stale = now() - 600;
+        let signature = WebhookSignature::sign(SignatureAlgorithm::Sha256, b"new", Some(stale), b"payload");
+        let resp = app(config.clone()).oneshot(request(signature, Some(stale), "payload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let untimed = WebhookSignature::sign(SignatureAlgorithm::Sha256, b"new", None, b"payload");
+        let resp = app(config).oneshot(request(untimed, None, "payload")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_body_too_large() {
+        let signature = WebhookSignature::sign(SignatureAlgorithm::Sha256, b"new", None, b"payload");
+        let resp = app(WebhookConfig::new().without_timestamp()).oneshot(request(signature, None, "x".repeat(2048))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}